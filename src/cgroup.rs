@@ -0,0 +1,146 @@
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Prepares a delegated cgroup v2 subtree to hold a cgroup per request, enabling the controllers
+/// that its limits need.
+pub fn setup(root: &Path, memory_max: Option<u64>, cpu_max: Option<&str>) -> std::io::Result<()> {
+    std::fs::create_dir_all(root)?;
+    let mut controllers = Vec::new();
+    if memory_max.is_some() {
+        controllers.push("+memory");
+    }
+    if cpu_max.is_some() {
+        controllers.push("+cpu");
+    }
+    if !controllers.is_empty() {
+        std::fs::write(root.join("cgroup.subtree_control"), controllers.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Moves the calling process into the cgroup whose `cgroup.procs` is at `procs`, ahead of exec,
+/// so the command and anything it starts are accounted to the cgroup.
+pub fn enter(procs: &CStr) -> std::io::Result<()> {
+    use nix::fcntl::{open, OFlag};
+    use nix::sys::stat::Mode;
+
+    let fd = open(procs, OFlag::O_WRONLY, Mode::empty())?;
+    // writing 0 moves the writer itself
+    let written = nix::unistd::write(fd, b"0");
+    nix::unistd::close(fd)?;
+    written?;
+    Ok(())
+}
+
+/// A cgroup holding a single command, and anything it starts.
+pub struct Cgroup {
+    path: PathBuf,
+    procs: CString,
+}
+
+/// What a command used, read from its cgroup once it has exited.
+#[derive(Debug, Default, PartialEq)]
+pub struct Usage {
+    pub cpu_usec: Option<u64>,
+    pub memory_peak: Option<u64>,
+}
+
+impl Cgroup {
+    pub fn create(
+        root: &Path,
+        memory_max: Option<u64>,
+        cpu_max: Option<&str>,
+    ) -> std::io::Result<Self> {
+        let path = root.join(scru128::new().to_string());
+        std::fs::create_dir(&path)?;
+        let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes()).unwrap();
+        let cgroup = Cgroup { path, procs };
+        let limited = (|| {
+            if let Some(memory_max) = memory_max {
+                std::fs::write(cgroup.path.join("memory.max"), memory_max.to_string())?;
+            }
+            if let Some(cpu_max) = cpu_max {
+                std::fs::write(cgroup.path.join("cpu.max"), cpu_max)?;
+            }
+            Ok(())
+        })();
+        match limited {
+            Ok(()) => Ok(cgroup),
+            Err(e) => {
+                cgroup.remove();
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes a cgroup that nothing was started in.
+    pub fn remove(self) {
+        let _ = std::fs::remove_dir(&self.path);
+    }
+
+    /// The path of the cgroup's `cgroup.procs`, ready to be handed to `enter`.
+    pub fn procs(&self) -> CString {
+        self.procs.clone()
+    }
+
+    /// Reads what the cgroup used, then kills anything left in it and removes it.
+    pub async fn finish(self) -> Usage {
+        let usage = self.usage();
+
+        let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..10 {
+            if std::fs::remove_dir(&self.path).is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        usage
+    }
+
+    fn usage(&self) -> Usage {
+        let read = |name: &str| std::fs::read_to_string(self.path.join(name)).ok();
+        Usage {
+            cpu_usec: read("cpu.stat").and_then(|stat| {
+                stat.lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))
+                    .and_then(|usec| usec.trim().parse().ok())
+            }),
+            memory_peak: read("memory.peak").and_then(|peak| peak.trim().parse().ok()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_usage() {
+        // a plain directory stands in for the cgroup filesystem
+        let root = tempfile::tempdir().unwrap();
+        let cgroup = Cgroup::create(root.path(), Some(1 << 20), Some("50000 100000")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(cgroup.path.join("memory.max")).unwrap(),
+            "1048576"
+        );
+        assert_eq!(
+            std::fs::read_to_string(cgroup.path.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+
+        std::fs::write(
+            cgroup.path.join("cpu.stat"),
+            "usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n",
+        )
+        .unwrap();
+        std::fs::write(cgroup.path.join("memory.peak"), "4096\n").unwrap();
+        assert_eq!(
+            cgroup.usage(),
+            Usage {
+                cpu_usec: Some(1234),
+                memory_peak: Some(4096),
+            }
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn encoder<W: AsyncWrite + Unpin + Send + 'static>(
+        &self,
+        writer: W,
+    ) -> Box<dyn AsyncWrite + Unpin + Send> {
+        match self {
+            Encoding::Brotli => Box::new(BrotliEncoder::new(writer)),
+            Encoding::Zstd => Box::new(ZstdEncoder::new(writer)),
+            Encoding::Gzip => Box::new(GzipEncoder::new(writer)),
+        }
+    }
+}
+
+/// Picks the encoding the client most prefers from its `Accept-Encoding`. Ties go to br, then
+/// zstd, then gzip.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+        let q = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let name = parts.next()?.trim();
+                if name != encoding.as_str() && name != "*" {
+                    return None;
+                }
+                // a q value that isn't a number from 0 to 1, such as nan, isn't acceptable
+                let q = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map(|q| {
+                        q.trim()
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|q| (0.0..=1.0).contains(q))
+                            .unwrap_or(0.0)
+                    })
+                    .unwrap_or(1.0);
+                // an explicit entry takes precedence over the wildcard
+                Some((name != "*", q))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether responses of this content type are worth compressing.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-ndjson"
+                | "image/svg+xml"
+        )
+}
+
+/// Whether a response can be compressed: the command hasn't set its own `content-encoding`,
+/// the content type is compressible and the body isn't known to be smaller than `min_size`.
+/// `min_size` only applies to bodies with a known length, from `len` or a `content-length`: a
+/// streamed body is always compressible.
+pub fn compressible(response: &http::header::HeaderMap, len: Option<u64>, min_size: u64) -> bool {
+    if response.contains_key("content-encoding") {
+        return false;
+    }
+    let content_type = response
+        .get("content-type")
+        .and_then(|content_type| content_type.to_str().ok());
+    if !content_type.is_some_and(is_compressible) {
+        return false;
+    }
+    let len = len.or_else(|| {
+        response
+            .get("content-length")
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+    });
+    len.is_none_or(|len| len >= min_size)
+}
+
+/// Picks the encoding for a compressible response from the request's `accept-encoding`.
+pub fn choose(request: &http::header::HeaderMap) -> Option<Encoding> {
+    let accept_encoding = request
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    negotiate(&accept_encoding)
+}
+
+/// Compresses everything read from `input`. The encoder is flushed after each read, so
+/// streaming output reaches the client as it's produced.
+pub fn encode(
+    input: impl AsyncRead + Unpin + Send + 'static,
+    encoding: Encoding,
+) -> tokio::io::DuplexStream {
+    let (reader, writer) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut input = input;
+        let mut encoder = encoding.encoder(writer);
+        let mut buf = [0; 4096];
+        loop {
+            let n = match input.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if encoder.write_all(&buf[..n]).await.is_err() || encoder.flush().await.is_err() {
+                return;
+            }
+        }
+        let _ = encoder.shutdown().await;
+    });
+    reader
+}
+
+/// Wraps `reader` to decode a request body sent with the given `content-encoding`, if it's one
+/// that's supported.
+pub fn decoder<'a>(
+    content_encoding: &str,
+    reader: impl AsyncBufRead + Unpin + Send + 'a,
+) -> Option<Box<dyn AsyncRead + Unpin + Send + 'a>> {
+    use async_compression::tokio::bufread;
+
+    Some(
+        match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Box::new(bufread::GzipDecoder::new(reader)),
+            // HTTP's deflate is zlib-wrapped
+            "deflate" => Box::new(bufread::ZlibDecoder::new(reader)),
+            "br" => Box::new(bufread::BrotliDecoder::new(reader)),
+            "zstd" => Box::new(bufread::ZstdDecoder::new(reader)),
+            _ => return None,
+        },
+    )
+}
+
+/// Compresses a body that's already in memory.
+pub async fn encode_all(data: &[u8], encoding: Encoding) -> Vec<u8> {
+    use async_compression::tokio::bufread;
+
+    let mut encoded = Vec::new();
+    match encoding {
+        Encoding::Brotli => {
+            bufread::BrotliEncoder::new(data)
+                .read_to_end(&mut encoded)
+                .await
+        }
+        Encoding::Zstd => {
+            bufread::ZstdEncoder::new(data)
+                .read_to_end(&mut encoded)
+                .await
+        }
+        Encoding::Gzip => {
+            bufread::GzipEncoder::new(data)
+                .read_to_end(&mut encoded)
+                .await
+        }
+    }
+    .unwrap();
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("zstd, gzip"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("*;q=0.1, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=nan, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=inf, br;q=0.5"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=nan"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("application/activity+json"));
+        assert!(!is_compressible("image/png"));
+    }
+
+    #[tokio::test]
+    async fn test_decoder() {
+        for encoding in [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+            let encoded = encode_all(b"hello", encoding).await;
+            let mut decoded = Vec::new();
+            decoder(encoding.as_str(), &encoded[..])
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .await
+                .unwrap();
+            assert_eq!(decoded, b"hello");
+        }
+        assert!(decoder("compress", &b""[..]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encode_streams() {
+        use async_compression::tokio::bufread::GzipDecoder;
+
+        let (input, mut output) = tokio::io::duplex(64);
+        let encoded = encode(input, Encoding::Gzip);
+        let mut decoded = GzipDecoder::new(tokio::io::BufReader::new(encoded));
+        decoded.multiple_members(true);
+
+        // each chunk can be decoded before the input is closed
+        output.write_all(b"hello\n").await.unwrap();
+        let mut buf = [0; 64];
+        let n = decoded.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello\n");
+
+        output.write_all(b"world\n").await.unwrap();
+        drop(output);
+        let mut rest = Vec::new();
+        decoded.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"world\n");
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreated, RulesetCreatedAttr,
+    RulesetStatus, ABI,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+/// The Landlock ABI whose filesystem rights are handled: up to and including truncation.
+const ABI_VERSION: ABI = ABI::V3;
+
+/// Asks `landlock_create_ruleset` for the kernel's ABI version rather than a ruleset.
+const LANDLOCK_CREATE_RULESET_VERSION: nix::libc::c_uint = 1;
+
+/// Landlock rules restricting the files commands can open to what's under a set of readable
+/// paths and a set of writable ones.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl Rules {
+    /// Checks the kernel has Landlock, and that the paths can be opened.
+    pub fn new(read: &[PathBuf], write: &[PathBuf]) -> Result<Self, String> {
+        let version = unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<u8>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if version < 1 {
+            return Err(
+                "--landlock-ro and --landlock-rw need a kernel with Landlock enabled".into(),
+            );
+        }
+        let rules = Rules {
+            read: read.to_vec(),
+            write: write.to_vec(),
+        };
+        rules.ruleset()?;
+        Ok(rules)
+    }
+
+    /// Builds a ruleset, ready to be handed to `restrict` in a command.
+    pub fn ruleset(&self) -> Result<RulesetCreated, String> {
+        let read = AccessFs::from_read(ABI_VERSION);
+        let all = AccessFs::from_all(ABI_VERSION);
+        Ruleset::default()
+            .handle_access(all)
+            .and_then(|ruleset| ruleset.create())
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&self.read, read)))
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&self.write, all)))
+            .map_err(|e| format!("landlock: {}", e))
+    }
+}
+
+/// Restricts the calling process to `ruleset`. Fails with EPERM when the kernel can't enforce it,
+/// rather than letting the command run unrestricted.
+pub fn restrict(ruleset: RulesetCreated) -> std::io::Result<()> {
+    match ruleset.restrict_self() {
+        Ok(status) if status.ruleset != RulesetStatus::NotEnforced => Ok(()),
+        _ => Err(std::io::Error::from_raw_os_error(nix::libc::EPERM)),
+    }
+}
+
+/// System calls that `--seccomp-deny` knows by name, and the groups it takes with an `@`.
+const SYSCALLS: &[(&str, &[(&str, nix::libc::c_long)])] = {
+    use nix::libc::*;
+    &[
+        (
+            "ptrace",
+            &[
+                ("ptrace", SYS_ptrace),
+                ("process_vm_readv", SYS_process_vm_readv),
+                ("process_vm_writev", SYS_process_vm_writev),
+            ],
+        ),
+        (
+            "mount",
+            &[
+                ("mount", SYS_mount),
+                ("umount2", SYS_umount2),
+                ("pivot_root", SYS_pivot_root),
+                ("chroot", SYS_chroot),
+                ("open_tree", SYS_open_tree),
+                ("move_mount", SYS_move_mount),
+                ("fsopen", SYS_fsopen),
+                ("fsconfig", SYS_fsconfig),
+                ("fsmount", SYS_fsmount),
+            ],
+        ),
+        (
+            "network",
+            &[
+                ("socket", SYS_socket),
+                ("socketpair", SYS_socketpair),
+                ("connect", SYS_connect),
+                ("bind", SYS_bind),
+                ("listen", SYS_listen),
+                ("accept", SYS_accept),
+                ("accept4", SYS_accept4),
+            ],
+        ),
+        (
+            "namespaces",
+            &[("unshare", SYS_unshare), ("setns", SYS_setns)],
+        ),
+        (
+            "system",
+            &[
+                ("reboot", SYS_reboot),
+                ("kexec_load", SYS_kexec_load),
+                ("init_module", SYS_init_module),
+                ("finit_module", SYS_finit_module),
+                ("delete_module", SYS_delete_module),
+                ("swapon", SYS_swapon),
+                ("swapoff", SYS_swapoff),
+                ("bpf", SYS_bpf),
+                ("perf_event_open", SYS_perf_event_open),
+                ("userfaultfd", SYS_userfaultfd),
+                ("keyctl", SYS_keyctl),
+                ("add_key", SYS_add_key),
+                ("request_key", SYS_request_key),
+            ],
+        ),
+    ]
+};
+
+/// Compiles a seccomp filter that kills a command with `SIGSYS` when it makes one of the `deny`
+/// system calls, each a name, or a group such as `@network`.
+pub fn seccomp(deny: &[String]) -> Result<BpfProgram, String> {
+    let mut rules = BTreeMap::new();
+    for name in deny {
+        let numbers: Vec<nix::libc::c_long> = match name.strip_prefix('@') {
+            Some(group) => SYSCALLS
+                .iter()
+                .find(|(g, _)| *g == group)
+                .map(|(_, calls)| calls.iter().map(|(_, number)| *number).collect())
+                .ok_or_else(|| {
+                    let groups: Vec<String> =
+                        SYSCALLS.iter().map(|(g, _)| format!("@{}", g)).collect();
+                    format!(
+                        "unknown system call group: {} (try {})",
+                        name,
+                        groups.join(", ")
+                    )
+                })?,
+            None => SYSCALLS
+                .iter()
+                .flat_map(|(_, calls)| calls.iter())
+                .find(|(call, _)| call == name)
+                .map(|(_, number)| vec![*number])
+                .ok_or_else(|| format!("unknown system call: {}", name))?,
+        };
+        for number in numbers {
+            rules.insert(number, vec![]);
+        }
+    }
+
+    let arch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| format!("seccomp: {}", e))?;
+    SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::KillProcess,
+        arch,
+    )
+    .and_then(BpfProgram::try_from)
+    .map_err(|e| format!("seccomp: {}", e))
+}
+
+/// Installs `filter` in the calling process, which keeps it across exec and passes it on to
+/// anything it starts.
+pub fn apply(filter: &BpfProgram) -> std::io::Result<()> {
+    seccompiler::apply_filter(filter)
+        .map_err(|_| std::io::Error::from_raw_os_error(nix::libc::EPERM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seccomp() {
+        assert!(seccomp(&["ptrace".to_string(), "@network".to_string()]).is_ok());
+        assert_eq!(
+            seccomp(&["nope".to_string()]),
+            Err("unknown system call: nope".to_string())
+        );
+        assert!(seccomp(&["@nope".to_string()])
+            .unwrap_err()
+            .starts_with("unknown system call group: @nope"));
+    }
+}
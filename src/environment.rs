@@ -0,0 +1,75 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Where commands run, and the environment they're given.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub cwd: Option<PathBuf>,
+    /// Start from an empty environment rather than the server's
+    pub clear: bool,
+    /// Variables kept from the server's environment when it's cleared, as they were at startup
+    pub kept: Vec<(String, OsString)>,
+    pub vars: Vec<(String, String)>,
+    /// Overrides PATH, after `vars`
+    pub path: Option<String>,
+}
+
+impl Environment {
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        if self.clear {
+            command.env_clear();
+            command.envs(self.kept.iter().map(|(key, value)| (key, value)));
+        }
+        command.envs(self.vars.iter().map(|(key, value)| (key, value)));
+        if let Some(path) = &self.path {
+            command.env("PATH", path);
+        }
+    }
+}
+
+/// Looks up the variables in `allow` that are set in `source`, usually `std::env::var_os`.
+pub fn keep(
+    allow: &[String],
+    source: impl Fn(String) -> Option<OsString>,
+) -> Vec<(String, OsString)> {
+    allow
+        .iter()
+        .filter_map(|name| Some((name.clone(), source(name.clone())?)))
+        .collect()
+}
+
+/// Parses a `KEY=VALUE` argument.
+pub fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_var() {
+        assert_eq!(
+            parse_var("KEY=a=b"),
+            Ok(("KEY".to_string(), "a=b".to_string()))
+        );
+        assert_eq!(parse_var("KEY="), Ok(("KEY".to_string(), "".to_string())));
+        assert!(parse_var("KEY").is_err());
+        assert!(parse_var("=value").is_err());
+    }
+
+    #[test]
+    fn test_keep() {
+        let source = |name: String| (name == "SET").then(|| OsString::from("value"));
+        assert_eq!(
+            keep(&["SET".to_string(), "UNSET".to_string()], source),
+            vec![("SET".to_string(), OsString::from("value"))]
+        );
+    }
+}
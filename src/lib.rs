@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request {
     pub stamp: scru128::Scru128Id,
     pub message: String,
@@ -22,13 +22,78 @@ pub struct Request {
     pub path: String,
     pub query: HashMap<String, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_event_id: Option<String>,
+    /// The request body's original `content-encoding`, when http-sh has decoded it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// The fields of a `multipart/form-data` body, when http-sh has parsed it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub form: Option<HashMap<String, Vec<FormField>>>,
+    /// A small urlencoded or JSON body, parsed, when http-sh has buffered it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+    /// With --max-concurrency, the number of requests that were waiting ahead of this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_depth: Option<usize>,
+    /// With --max-concurrency, how long this request waited for its turn.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_wait_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub response: Option<Response>,
+    /// With --cgroup, the CPU time the command used, in microseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_usec: Option<u64>,
+    /// With --cgroup, the command's peak memory use, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_peak: Option<u64>,
+}
+
+/// A `multipart/form-data` field: either a plain value, or a file spooled to disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FormField {
+    File {
+        path: std::path::PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        size: u64,
+    },
+    Value(String),
 }
 
-#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Response {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<std::collections::HashMap<String, String>>,
+    /// Redirect the client to this location. Defaults the status to 302, or 301 when `permanent`
+    /// is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permanent: Option<bool>,
+    /// An inline response body. When set, the command's stdout is discarded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Serve this file as the response body, in place of stdout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<std::path::PathBuf>,
+    /// Re-dispatch the request to this path, as though the client had requested it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal: Option<String>,
+    /// Accept a WebSocket upgrade, bridging messages to stdin and stdout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub websocket: Option<bool>,
+    /// Bridge WebSocket messages as frames rather than lines, so they can hold any bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub websocket_frames: Option<bool>,
+    /// Frame each line of stdout as a server-sent event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sse: Option<bool>,
+    /// Answer with the events published to this topic, as server-sent events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscribe: Option<String>,
 }
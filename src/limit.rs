@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps the number of commands running at once. Requests over the cap wait their turn in a
+/// bounded queue; tokio's semaphore hands out permits in FIFO order.
+pub struct Limiter {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_size: usize,
+    timeout: Duration,
+}
+
+/// How a request fared getting a slot to run its command.
+pub enum Admission {
+    Admitted {
+        permit: OwnedSemaphorePermit,
+        /// The number of requests already waiting when this one arrived
+        depth: usize,
+        wait: Duration,
+    },
+    QueueFull,
+    TimedOut {
+        wait: Duration,
+    },
+}
+
+impl Limiter {
+    pub fn new(max_concurrency: usize, queue_size: usize, timeout: Duration) -> Self {
+        Limiter {
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            queued: AtomicUsize::new(0),
+            queue_size,
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub async fn admit(&self) -> Admission {
+        let start = Instant::now();
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Admission::Admitted {
+                permit,
+                depth: 0,
+                wait: Duration::ZERO,
+            };
+        }
+
+        let depth = self.queued.fetch_add(1, Ordering::SeqCst);
+        if depth >= self.queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Admission::QueueFull;
+        }
+        let permit = tokio::time::timeout(self.timeout, self.permits.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        match permit {
+            Ok(permit) => Admission::Admitted {
+                permit: permit.unwrap(),
+                depth,
+                wait: start.elapsed(),
+            },
+            Err(_) => Admission::TimedOut {
+                wait: start.elapsed(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admit() {
+        let limiter = Arc::new(Limiter::new(1, 1, Duration::from_millis(100)));
+
+        let Admission::Admitted { permit, depth, .. } = limiter.admit().await else {
+            panic!("expected to be admitted");
+        };
+        assert_eq!(depth, 0);
+
+        // the next request waits in the queue, until the first is done
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.admit().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // with the queue full, a third is turned away
+        assert!(matches!(limiter.admit().await, Admission::QueueFull));
+
+        drop(permit);
+        let Admission::Admitted {
+            permit,
+            depth,
+            wait,
+        } = queued.await.unwrap()
+        else {
+            panic!("expected to be admitted");
+        };
+        assert_eq!(depth, 0);
+        assert!(wait >= Duration::from_millis(20));
+
+        // a request that waits too long gives up
+        assert!(matches!(limiter.admit().await, Admission::TimedOut { .. }));
+        drop(permit);
+    }
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::ffi::OsString;
 use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
-use std::path::PathBuf;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use futures::TryStreamExt as _;
 
@@ -12,12 +15,26 @@ use tokio::sync::watch;
 
 use serde_json::json;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 
 use command_fds::tokio::CommandFdAsyncExt;
 use command_fds::FdMapping;
 
+mod cgroup;
+mod compress;
+mod confine;
+mod environment;
+mod limit;
 mod listener;
+mod multipart;
+mod privileges;
+mod pty;
+mod sandbox;
+mod sse;
+mod topics;
+mod warm;
+mod websocket;
+mod worker;
 use http_sh::{Request, Response};
 
 #[derive(Parser, Debug, Clone)]
@@ -27,11 +44,287 @@ struct Args {
     #[clap(short, long, value_parser)]
     static_path: Option<PathBuf>,
 
+    /// Restrict files served via the response metadata `file` field to this directory. Can be
+    /// given multiple times. When omitted, any path readable by the server can be served, so it's
+    /// required along with --user, --group, --sandbox and --landlock-*
+    #[clap(long, value_parser, value_name = "DIR")]
+    file_root: Vec<PathBuf>,
+
     /// Path to a PEM-encoded file with your TLS private key and certificates. When provided, the
     /// server will use HTTPS, otherwise HTTP
     #[clap(short, long, value_parser, value_name = "PEM_FILE")]
     tls: Option<PathBuf>,
 
+    /// Maximum number of internal redirects to follow for a single request
+    #[clap(long, value_parser, value_name = "COUNT", default_value_t = 10)]
+    max_internal_redirects: usize,
+
+    /// Give the command fd 5 to write response trailers to, as JSON, and fd 6 to read request
+    /// trailers from once the request body is complete
+    #[clap(long)]
+    trailers: bool,
+
+    /// Wait for the command to close fd 4 before starting the response. By default the response
+    /// starts on the first complete JSON document on fd 4, or the first output on stdout
+    #[clap(long)]
+    wait_fd4_close: bool,
+
+    /// Frame each response as server-sent events, as though the command set `sse` in its
+    /// response metadata
+    #[clap(long)]
+    sse: bool,
+
+    /// Seconds of quiet before a server-sent events response gets a keep-alive comment
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 15)]
+    sse_keep_alive: u64,
+
+    /// Give the command fd 7 to publish events to topics on, one JSON object per line, and let
+    /// it answer with a topic's events via `subscribe` in its response metadata
+    #[clap(long)]
+    topics: bool,
+
+    /// Number of recent events each topic keeps for subscribers resuming from a Last-Event-ID
+    #[clap(long, value_parser, value_name = "COUNT", default_value_t = 100)]
+    topic_replay: usize,
+
+    /// Seconds a topic keeps its events after the last is published. Once they've expired and
+    /// it has no subscribers, the topic is dropped
+    #[clap(long, value_parser, value_name = "SECS", default_value_t = 300)]
+    topic_ttl: u64,
+
+    /// Compress responses with br, zstd or gzip, as negotiated from the request's
+    /// Accept-Encoding. A command can opt out by setting its own content-encoding
+    #[clap(long)]
+    compress: bool,
+
+    /// Responses known to be smaller than this many bytes aren't compressed. Only applies to
+    /// bodies with a known length
+    #[clap(long, value_parser, value_name = "BYTES", default_value_t = 1024)]
+    compress_min_size: u64,
+
+    /// Decode gzip, deflate, br and zstd request bodies before they're passed to stdin
+    #[clap(long)]
+    decompress: bool,
+
+    /// Decoded request bodies are cut off at this many bytes: stdin is closed early and the
+    /// command is sent SIGURG
+    #[clap(long, value_parser, value_name = "BYTES", default_value_t = 64 * 1024 * 1024)]
+    max_decompressed_size: u64,
+
+    /// Requests declaring a larger body are answered 413 without running the command. Longer
+    /// chunked bodies are cut off: stdin is closed early and the command is sent SIGURG
+    #[clap(long, value_parser, value_name = "BYTES")]
+    max_body_size: Option<u64>,
+
+    /// Requests with more headers than this are answered 431. hyper turns away requests with
+    /// over 100 itself, so that's also the most this can be
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u16).range(1..=100),
+        value_name = "COUNT",
+        default_value_t = 100
+    )]
+    max_headers: u16,
+
+    /// Requests with more header bytes than this are answered 431
+    #[clap(long, value_parser, value_name = "BYTES", default_value_t = 16 * 1024)]
+    max_header_size: usize,
+
+    /// Hold the body of `Expect: 100-continue` requests until the command has written its
+    /// response metadata or output. A status of 400 or above rejects the body unread
+    #[clap(long, conflicts_with_all = ["parse_body", "multipart"])]
+    expect_continue: bool,
+
+    /// Seconds to hold a body for --expect-continue before passing it along anyway, for
+    /// commands that read stdin before they respond
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECS",
+        default_value_t = 1,
+        requires = "expect_continue"
+    )]
+    expect_continue_timeout: u64,
+
+    /// Parse multipart/form-data bodies, describing their fields on fd 3 and spooling uploaded
+    /// files to a temporary directory that's removed once the command exits
+    #[clap(long)]
+    multipart: bool,
+
+    /// Parse urlencoded and JSON bodies into `body` on fd 3. Stdin still gets the raw body
+    #[clap(long)]
+    parse_body: bool,
+
+    /// Only bodies up to this size are parsed by --parse-body
+    #[clap(long, value_parser, value_name = "BYTES", default_value_t = 64 * 1024)]
+    parse_body_max_size: u64,
+
+    /// Run at most this many commands at once. Further requests wait in a queue
+    #[clap(long, value_parser, value_name = "COUNT")]
+    max_concurrency: Option<usize>,
+
+    /// Requests that arrive with this many already waiting are answered 503
+    #[clap(long, value_parser, value_name = "COUNT", default_value_t = 100)]
+    queue_size: usize,
+
+    /// Requests that wait longer than this are answered 503
+    #[clap(long, value_parser, value_name = "SECONDS", default_value_t = 30)]
+    queue_timeout: u64,
+
+    /// Keep this many long-lived copies of the command running, each handling one request at a
+    /// time over a framed protocol on stdin and stdout
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        value_name = "COUNT",
+        conflicts_with_all = [
+            "max_body_size",
+            "decompress",
+            "expect_continue",
+            "sse",
+            "compress",
+            "trailers",
+            "topics",
+            "prespawn",
+            "user",
+            "group",
+            "sandbox",
+            "landlock_ro",
+            "landlock_rw",
+            "seccomp_deny",
+            "rlimit_as",
+            "rlimit_cpu",
+            "rlimit_nofile",
+            "rlimit_nproc",
+            "rlimit_fsize",
+            "cgroup",
+            "pty",
+        ]
+    )]
+    workers: Option<usize>,
+
+    /// Replace a worker after it has handled this many requests
+    #[clap(long, value_parser, value_name = "COUNT")]
+    worker_max_requests: Option<usize>,
+
+    /// Keep this many copies of the command spawned ahead of time, blocked on reading fd 3, to be
+    /// handed the next requests
+    #[clap(
+        long,
+        value_parser,
+        value_name = "COUNT",
+        conflicts_with = "max_concurrency"
+    )]
+    prespawn: Option<usize>,
+
+    /// Limit the command's address space (RLIMIT_AS)
+    #[clap(long, value_parser, value_name = "BYTES")]
+    rlimit_as: Option<u64>,
+
+    /// Limit the command's CPU time (RLIMIT_CPU). It's sent SIGXCPU when it runs over, and
+    /// SIGKILL a second later
+    #[clap(long, value_parser, value_name = "SECONDS")]
+    rlimit_cpu: Option<u64>,
+
+    /// Limit the command's open file descriptors (RLIMIT_NOFILE)
+    #[clap(long, value_parser, value_name = "COUNT")]
+    rlimit_nofile: Option<u64>,
+
+    /// Limit the processes the command's user can run (RLIMIT_NPROC)
+    #[clap(long, value_parser, value_name = "COUNT")]
+    rlimit_nproc: Option<u64>,
+
+    /// Limit the size of files the command writes (RLIMIT_FSIZE)
+    #[clap(long, value_parser, value_name = "BYTES")]
+    rlimit_fsize: Option<u64>,
+
+    /// Place each command in its own cgroup, created under this delegated cgroup v2 directory.
+    /// Its CPU time and peak memory are added to the exit log
+    #[clap(long, value_parser, value_name = "DIR")]
+    cgroup: Option<PathBuf>,
+
+    /// Set memory.max on each command's cgroup
+    #[clap(long, value_parser, value_name = "BYTES", requires = "cgroup")]
+    cgroup_memory_max: Option<u64>,
+
+    /// Set cpu.max on each command's cgroup, e.g. "50000 100000" for half a CPU
+    #[clap(long, value_parser, value_name = "QUOTA PERIOD", requires = "cgroup")]
+    cgroup_cpu_max: Option<String>,
+
+    /// Run commands as this user, by name or uid. http-sh must be started as root
+    #[clap(long, value_parser, value_name = "USER")]
+    user: Option<String>,
+
+    /// Run commands as this group, by name or gid [default: the user's primary group]
+    #[clap(long, value_parser, value_name = "GROUP")]
+    group: Option<String>,
+
+    /// Once listening, switch http-sh itself to --user and --group
+    #[clap(long, requires = "user")]
+    drop_privileges: bool,
+
+    /// Run commands in new user, mount and PID namespaces, with a read-only view of the allowed
+    /// paths and a private /tmp
+    #[clap(long, conflicts_with = "drop_privileges")]
+    sandbox: bool,
+
+    /// Bind this path read-only into the sandbox. Can be repeated
+    /// [default: /bin, /sbin, /usr, /lib, /lib32, /lib64 and /etc]
+    #[clap(long, value_parser, value_name = "PATH", requires = "sandbox")]
+    sandbox_ro: Vec<PathBuf>,
+
+    /// Also give sandboxed commands a network namespace of their own, leaving them no network
+    #[clap(long, requires = "sandbox")]
+    sandbox_no_network: bool,
+
+    /// Let commands read and execute files under this path, using Landlock. Once either
+    /// --landlock-ro or --landlock-rw is given, nothing else can be opened. Can be repeated
+    #[clap(long, value_parser, value_name = "PATH")]
+    landlock_ro: Vec<PathBuf>,
+
+    /// Let commands read, write, create and remove files under this path. Can be repeated
+    #[clap(long, value_parser, value_name = "PATH")]
+    landlock_rw: Vec<PathBuf>,
+
+    /// Kill commands with SIGSYS when they make this system call, or one of a group: @ptrace,
+    /// @mount, @network, @namespaces or @system. Can be repeated
+    #[clap(long, value_parser, value_name = "SYSCALL")]
+    seccomp_deny: Vec<String>,
+
+    /// Run commands in this directory [default: the directory http-sh was started in]
+    #[clap(long, value_parser, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+
+    /// Add this variable to commands' environment. Can be repeated
+    #[clap(long, value_parser = environment::parse_var, value_name = "KEY=VALUE")]
+    env: Vec<(String, String)>,
+
+    /// Start commands with an empty environment, rather than a copy of http-sh's
+    #[clap(long)]
+    env_clear: bool,
+
+    /// Keep this variable from http-sh's environment with --env-clear. Can be repeated
+    #[clap(long, value_parser, value_name = "NAME", requires = "env_clear")]
+    env_allow: Vec<String>,
+
+    /// Set commands' PATH, overriding any other
+    #[clap(long, value_parser, value_name = "PATH")]
+    path: Option<String>,
+
+    /// Give commands a pseudo-terminal as stdout, for tools that buffer their output or won't
+    /// run without one
+    #[clap(long)]
+    pty: bool,
+
+    /// The size of the pseudo-terminal
+    #[clap(long, value_parser = pty::parse_size, value_name = "COLSxROWS", default_value = "80x24", requires = "pty")]
+    pty_size: nix::pty::Winsize,
+
+    /// Translate line endings in the pseudo-terminal's output to \r\n, as a terminal does, rather
+    /// than passing it through as is
+    #[clap(long, requires = "pty")]
+    pty_cooked: bool,
+
     /// Address to listen on [HOST]:PORT or <PATH> for Unix domain socket
     #[clap(value_parser, value_name = "LISTEN_ADDR")]
     listen: String,
@@ -42,14 +335,145 @@ struct Args {
     args: Vec<String>,
 }
 
+/// State shared across connections
+#[derive(Clone, Default)]
+struct State {
+    topics: topics::Hub,
+    limiter: Option<Arc<limit::Limiter>>,
+    workers: Option<Arc<worker::Pool>>,
+    warm: Option<Arc<warm::Pool<Option<Spawned>>>>,
+    launch: Arc<Launch>,
+}
+
+impl State {
+    fn new(args: &Args, launch: Launch) -> Self {
+        let launch = Arc::new(launch);
+        State {
+            topics: topics::Hub::new(
+                args.topic_replay,
+                std::time::Duration::from_secs(args.topic_ttl),
+            ),
+            limiter: args.max_concurrency.map(|max| {
+                Arc::new(limit::Limiter::new(
+                    max,
+                    args.queue_size,
+                    std::time::Duration::from_secs(args.queue_timeout),
+                ))
+            }),
+            workers: args.workers.map(|size| {
+                Arc::new(worker::Pool::new(
+                    &args.command,
+                    &args.args,
+                    launch.environment.clone(),
+                    size,
+                    args.worker_max_requests,
+                ))
+            }),
+            warm: args.prespawn.filter(|size| *size > 0).map(|size| {
+                let args = args.clone();
+                let launch = launch.clone();
+                Arc::new(warm::Pool::new(size, move || {
+                    spawn(&args, &launch, None)
+                        .map_err(|e| log_spawn_error(None, &e))
+                        .ok()
+                }))
+            }),
+            launch,
+        }
+    }
+}
+
+/// What's prepared at startup for each command to be spawned with
+#[derive(Default)]
+struct Launch {
+    /// --user and --group, resolved
+    identity: Option<privileges::Identity>,
+    /// --sandbox, once its namespaces are known to be available
+    sandbox: Option<Arc<sandbox::Sandbox>>,
+    /// --landlock-ro and --landlock-rw, checked
+    landlock: Option<confine::Rules>,
+    /// --seccomp-deny, compiled
+    seccomp: Option<Arc<seccompiler::BpfProgram>>,
+    environment: environment::Environment,
+}
+
+impl Launch {
+    /// Prepares what `args` asks for, taking the variables kept by --env-allow from `env`.
+    fn new(args: &Args, env: impl Fn(String) -> Option<OsString>) -> Result<Self, String> {
+        if let Some(cwd) = args.cwd.as_ref().filter(|cwd| !cwd.is_dir()) {
+            return Err(format!("--cwd {} isn't a directory", cwd.display()));
+        }
+        let confined = args.user.is_some()
+            || args.group.is_some()
+            || args.sandbox
+            || !args.landlock_ro.is_empty()
+            || !args.landlock_rw.is_empty();
+        if confined && args.file_root.is_empty() {
+            // files are opened by the server, which the command's confinement doesn't apply to
+            return Err(
+                "--file-root is required with --user, --group, --sandbox or --landlock-*".into(),
+            );
+        }
+        let identity = privileges::resolve(args.user.as_deref(), args.group.as_deref())?;
+        let sandbox = match args.sandbox {
+            true => {
+                let sandbox = sandbox::Sandbox::new(
+                    &args.sandbox_ro,
+                    !args.sandbox_no_network,
+                    args.cwd.as_deref(),
+                )?;
+                sandbox.probe()?;
+                Some(Arc::new(sandbox))
+            }
+            false => None,
+        };
+        let landlock = match args.landlock_ro.is_empty() && args.landlock_rw.is_empty() {
+            true => None,
+            false => Some(confine::Rules::new(&args.landlock_ro, &args.landlock_rw)?),
+        };
+        let seccomp = match args.seccomp_deny.is_empty() {
+            true => None,
+            false => Some(Arc::new(confine::seccomp(&args.seccomp_deny)?)),
+        };
+        Ok(Launch {
+            identity,
+            sandbox,
+            landlock,
+            seccomp,
+            environment: environment::Environment {
+                cwd: args.cwd.clone(),
+                clear: args.env_clear,
+                kept: environment::keep(&args.env_allow, env),
+                vars: args.env.clone(),
+                path: args.path.clone(),
+            },
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    let launch = Launch::new(&args, std::env::var_os).unwrap_or_else(|e| {
+        Args::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit()
+    });
+    if let Some(root) = &args.cgroup {
+        cgroup::setup(root, args.cgroup_memory_max, args.cgroup_cpu_max.as_deref())
+            .unwrap_or_else(|e| panic!("failed to set up cgroup {}: {}", root.display(), e));
+    }
+    let state = State::new(&args, launch);
 
     let accept_tls = args.tls.clone().map(configure_tls);
 
     let mut server = listener::Listener::bind(&args.listen).await.unwrap();
 
+    if args.drop_privileges {
+        privileges::assume(state.launch.identity.as_ref().unwrap())
+            .unwrap_or_else(|e| panic!("failed to drop privileges: {}", e));
+    }
+
     let is_unix = match server {
         listener::Listener::Tcp(_) => false,
         listener::Listener::Unix(_) => true,
@@ -83,8 +507,16 @@ async fn main() {
         json!({"stamp": scru128::new(), "message": "start", "address": format!("{}", server)})
     );
 
+    // hyper's read buffer has a floor of 8 KiB; anything that fits is checked by `handler`
+    let max_header_size = args.max_header_size;
+    let mut http = hyper::server::conn::Http::new();
+    http.max_buf_size(max_header_size.max(8 * 1024))
+        .http2_max_header_list_size(max_header_size as u32);
+
     loop {
         let args = args.clone();
+        let state = state.clone();
+        let http = http.clone();
         let accept_tls = accept_tls.clone();
 
         let (stream, remote_addr) = server.accept().await.unwrap();
@@ -99,27 +531,17 @@ async fn main() {
 
         let svc_fn = hyper::service::service_fn(move |req| {
             let args = args.clone();
+            let state = state.clone();
             let shutdown_rx = shutdown_rx.clone();
             async move {
                 Ok::<hyper::Response<hyper::Body>, Infallible>(
-                    handler(
-                        shutdown_rx,
-                        req,
-                        remote_addr,
-                        &args.static_path,
-                        &args.command,
-                        &args.args,
-                    )
-                    .await,
+                    handler(shutdown_rx, req, remote_addr, &args, &state).await,
                 )
             }
         });
 
         tokio::task::spawn(async move {
-            match hyper::server::conn::Http::new()
-                .serve_connection(stream, svc_fn)
-                .await
-            {
+            match http.serve_connection(stream, svc_fn).with_upgrades().await {
                 Ok(_) => (),
                 Err(e) => {
                     if e.is_incomplete_message() {
@@ -144,49 +566,226 @@ async fn main() {
     }
 }
 
+enum Dispatch {
+    Response(hyper::Response<hyper::Body>),
+    /// The command asked for the request to be re-dispatched to another path
+    Internal(hyper::Request<hyper::Body>),
+}
+
 async fn handler(
-    mut shutdown_rx: watch::Receiver<bool>,
-    req: hyper::Request<hyper::Body>,
+    shutdown_rx: watch::Receiver<bool>,
+    mut req: hyper::Request<hyper::Body>,
     addr: Option<SocketAddr>,
-    static_path: &Option<PathBuf>,
-    command: &String,
-    args: &Vec<String>,
+    args: &Args,
+    state: &State,
 ) -> hyper::Response<hyper::Body> {
-    if let Some(static_path) = static_path {
+    if let Some(res) = check_limits(&req, args) {
+        return res;
+    }
+
+    // the request's turn is taken once, by the first hop to need it, and kept for the rest
+    let mut turn = None;
+    for _ in 0..=args.max_internal_redirects {
+        match dispatch(shutdown_rx.clone(), req, addr, args, state, &mut turn).await {
+            Dispatch::Response(res) => return res,
+            Dispatch::Internal(next) => req = next,
+        }
+    }
+
+    hyper::Response::builder()
+        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+        .body("internal redirect limit exceeded\n".into())
+        .unwrap()
+}
+
+/// Rejects requests whose headers, or declared body, exceed the configured limits.
+fn check_limits(
+    req: &hyper::Request<hyper::Body>,
+    args: &Args,
+) -> Option<hyper::Response<hyper::Body>> {
+    let error = |status: http::StatusCode, body: &'static str| {
+        Some(
+            hyper::Response::builder()
+                .status(status)
+                .body(body.into())
+                .unwrap(),
+        )
+    };
+
+    let headers = req.headers();
+    let header_size: usize = headers
+        .iter()
+        .map(|(key, value)| key.as_str().len() + value.len())
+        .sum();
+    if headers.len() > args.max_headers as usize || header_size > args.max_header_size {
+        return error(
+            http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "request headers too large\n",
+        );
+    }
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let (Some(max), Some(content_length)) = (args.max_body_size, content_length) {
+        if content_length > max {
+            return error(
+                http::StatusCode::PAYLOAD_TOO_LARGE,
+                "request body too large\n",
+            );
+        }
+    }
+
+    None
+}
+
+/// A request's turn to run commands under --max-concurrency. Internal redirects share it, so
+/// the request only queues once, and holds a single slot until the last of its commands exits.
+#[derive(Clone)]
+struct Turn {
+    permit: Arc<tokio::sync::OwnedSemaphorePermit>,
+    depth: usize,
+    wait_ms: u64,
+}
+
+async fn dispatch(
+    shutdown_rx: watch::Receiver<bool>,
+    mut req: hyper::Request<hyper::Body>,
+    addr: Option<SocketAddr>,
+    args: &Args,
+    state: &State,
+    turn: &mut Option<Turn>,
+) -> Dispatch {
+    if let Some(static_path) = &args.static_path {
         let resolved = hyper_staticfile::resolve(&static_path, &req).await.unwrap();
         if let hyper_staticfile::ResolveResult::Found(_, _, _) = resolved {
-            return hyper_staticfile::ResponseBuilder::new()
-                .request(&req)
-                .build(resolved)
-                .unwrap();
+            return Dispatch::Response(
+                hyper_staticfile::ResponseBuilder::new()
+                    .request(&req)
+                    .build(resolved)
+                    .unwrap(),
+            );
         }
     }
 
-    let (req_reader, mut req_writer) = tokio_pipe::pipe().unwrap();
-    let (mut res_reader, res_writer) = tokio_pipe::pipe().unwrap();
+    // the request waits its turn before anything is read or spawned
+    if let (Some(limiter), None) = (&state.limiter, &turn) {
+        let unavailable = |reason: &str, wait: Option<u64>| {
+            println!(
+                "{}",
+                json!({
+                    "stamp": scru128::new(),
+                    "message": "unavailable",
+                    "reason": reason,
+                    "method": req.method().as_str(),
+                    "uri": req.uri().to_string(),
+                    "queue_wait_ms": wait,
+                })
+            );
+            Dispatch::Response(
+                hyper::Response::builder()
+                    .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .header("retry-after", limiter.timeout().as_secs().max(1))
+                    .body(format!("{}\n", reason).into())
+                    .unwrap(),
+            )
+        };
+        match limiter.admit().await {
+            limit::Admission::Admitted {
+                permit: admitted,
+                depth,
+                wait,
+            } => {
+                *turn = Some(Turn {
+                    permit: Arc::new(admitted),
+                    depth,
+                    wait_ms: wait.as_millis() as u64,
+                });
+            }
+            limit::Admission::QueueFull => return unavailable("queue full", None),
+            limit::Admission::TimedOut { wait } => {
+                return unavailable("queue timeout", Some(wait.as_millis() as u64))
+            }
+        }
+    }
 
-    let mut p = tokio::process::Command::new(command)
-        .args(args)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .fd_mappings(vec![
-            FdMapping {
-                parent_fd: req_reader.as_raw_fd(),
-                child_fd: 3,
-            },
-            FdMapping {
-                parent_fd: res_writer.as_raw_fd(),
-                child_fd: 4,
-            },
-        ])
-        .unwrap()
-        .spawn()
-        .expect("failed to spawn");
+    // a multipart body is read in full up front, so its fields can be described on fd 3
+    let mut form = None;
+    let mut spool = None;
+    let boundary = multipart::boundary(req.headers())
+        .filter(|_| args.multipart && !hyper::body::HttpBody::is_end_stream(req.body()));
+    if let Some(boundary) = boundary {
+        let body = std::mem::take(req.body_mut());
+        let spooled = async {
+            let dir = tempfile::tempdir()?;
+            let fields = multipart::parse(body, boundary, dir.path(), args.max_body_size).await?;
+            // uploads are handed over to the user the command runs as
+            if let Some(identity) = &state.launch.identity {
+                let owner = (Some(identity.uid.as_raw()), Some(identity.gid.as_raw()));
+                std::os::unix::fs::chown(dir.path(), owner.0, owner.1)?;
+                for field in fields.values().flatten() {
+                    if let http_sh::FormField::File { path, .. } = field {
+                        std::os::unix::fs::chown(path, owner.0, owner.1)?;
+                    }
+                }
+            }
+            Ok::<_, multipart::Error>((dir, fields))
+        };
+        match spooled.await {
+            Ok((dir, fields)) => {
+                form = Some(fields);
+                spool = Some(dir);
+            }
+            Err(e) => {
+                return Dispatch::Response(
+                    hyper::Response::builder()
+                        .status(e.status())
+                        .body(format!("{}\n", e).into())
+                        .unwrap(),
+                );
+            }
+        }
+    }
 
-    drop(req_reader);
-    drop(res_writer);
+    // a small body is buffered so it can be described on fd 3, and is then replayed to stdin
+    // ahead of the rest. A chunked body is read until it's over the cap, and left unparsed if it is
+    let mut parsed_body = None;
+    let mut body_prefix = Vec::new();
+    let content_length = req
+        .headers()
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let format = body_format(req.headers()).filter(|_| {
+        args.parse_body
+            && form.is_none()
+            && !req.headers().contains_key("content-encoding")
+            && content_length.is_none_or(|len| len <= args.parse_body_max_size)
+    });
+    if let Some(format) = format {
+        let complete = match read_prefix(req.body_mut(), args.parse_body_max_size).await {
+            Ok((prefix, complete)) => {
+                body_prefix = prefix;
+                complete
+            }
+            Err(e) => {
+                return Dispatch::Response(
+                    hyper::Response::builder()
+                        .status(http::StatusCode::BAD_REQUEST)
+                        .body(format!("{}\n", e).into())
+                        .unwrap(),
+                );
+            }
+        };
+        if complete {
+            parsed_body = parse_body(format, &body_prefix);
+        }
+    }
 
+    let on_upgrade = websocket::is_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
     let (req_parts, req_body) = req.into_parts();
+    let version = req_parts.version;
 
     let uri = req_parts.uri.clone().into_parts();
 
@@ -197,6 +796,12 @@ async fn handler(
             .map(|a| a.to_str().unwrap().to_owned())
     });
 
+    let last_event_id = req_parts
+        .headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .map(|id| id.to_owned());
+
     let path = req_parts.uri.path().to_string();
     let query: HashMap<String, String> = req_parts
         .uri
@@ -220,11 +825,148 @@ async fn handler(
         uri: req_parts.uri,
         path,
         query,
+        last_event_id,
+        content_encoding: None,
+        form,
+        body: parsed_body,
+        queue_depth: turn.as_ref().map(|turn| turn.depth),
+        queue_wait_ms: turn.as_ref().map(|turn| turn.wait_ms),
         response: None,
+        cpu_usec: None,
+        memory_peak: None,
+    };
+
+    if let Some(pool) = &state.workers {
+        let req_json = serde_json::to_string(&req_meta).unwrap();
+        let resources = Resources {
+            stamp: req_meta.stamp,
+            spool,
+            permit: turn.as_ref().map(|turn| turn.permit.clone()),
+            cgroup: None,
+            request: None,
+        };
+        let res = match pool.serve(req_json, body_prefix, req_body, resources).await {
+            Ok((res_meta, body)) => {
+                let res = match invalid_redirect(&res_meta) {
+                    Some(error) => {
+                        log_metadata_error(req_meta.stamp, &error);
+                        metadata_error()
+                    }
+                    None => worker_response(&res_meta, body),
+                };
+                req_meta.response = Some(Response {
+                    status: Some(res.status().as_u16()),
+                    ..res_meta
+                });
+                res
+            }
+            Err(e) => hyper::Response::builder()
+                .status(e.status())
+                .body(format!("{}\n", e).into())
+                .unwrap(),
+        };
+        println!("{}", serde_json::to_string(&req_meta).unwrap());
+        return Dispatch::Response(res);
+    }
+
+    // a warm command is already running, blocked on reading fd 3. One that's sandboxed can't be
+    // shown the request's uploads, so it's left for the next request
+    let mut spawned = None;
+    let warm = state
+        .warm
+        .as_ref()
+        .filter(|_| spool.is_none() || state.launch.sandbox.is_none());
+    while let Some(warm) = warm.and_then(|warm| warm.take()) {
+        // one that couldn't be spawned ahead of time is tried again for this request
+        let Some(mut warm) = warm else {
+            break;
+        };
+        if let Ok(None) = warm.p.try_wait() {
+            spawned = Some(warm);
+            break;
+        }
+    }
+    let spawned = match spawned {
+        Some(spawned) => Ok(spawned),
+        None => spawn(
+            args,
+            &state.launch,
+            spool.as_ref().map(|spool| spool.path()),
+        ),
+    };
+    let Spawned {
+        mut p,
+        pty,
+        mut req_writer,
+        res_reader,
+        res_trailers,
+        req_trailers,
+        publish,
+        cgroup,
+    } = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            log_spawn_error(Some(req_meta.stamp), &e);
+            return Dispatch::Response(
+                hyper::Response::builder()
+                    .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body("failed to spawn command\n".into())
+                    .unwrap(),
+            );
+        }
+    };
+    if let Some(publish_reader) = publish {
+        let topics = state.topics.clone();
+        let stamp = req_meta.stamp;
+        tokio::spawn(async move { topics.publish_lines(publish_reader, stamp).await });
+    }
+
+    // a body that will be decoded is described to the command as it will read it
+    let decode = req_meta
+        .headers
+        .get("content-encoding")
+        .and_then(|encoding| encoding.to_str().ok())
+        .filter(|encoding| args.decompress && compress::decoder(encoding, &b""[..]).is_some())
+        .map(|encoding| encoding.to_string());
+    if decode.is_some() {
+        req_meta.headers.remove("content-encoding");
+        req_meta.headers.remove("content-length");
+        req_meta.content_encoding = decode.clone();
+    }
+    let max_decompressed_size = args.max_decompressed_size;
+    let max_body_size = args.max_body_size;
+    let continue_timeout = std::time::Duration::from_secs(args.expect_continue_timeout);
+    let pid = p.id();
+    let stamp = req_meta.stamp;
+    let mut resources = Resources {
+        stamp: req_meta.stamp,
+        spool,
+        permit: turn.as_ref().map(|turn| turn.permit.clone()),
+        cgroup,
+        request: None,
+    };
+
+    // hyper sends `100 Continue` when the body is first read, so holding off on reading it lets
+    // the command turn the body away
+    let expects_continue = req_meta
+        .headers
+        .get("expect")
+        .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let (continue_tx, continue_rx) = match args.expect_continue && expects_continue {
+        true => {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            (Some(tx), Some(rx))
+        }
+        false => (None, None),
     };
 
     let req_json = serde_json::to_string(&req_meta).unwrap();
-    let mut stdin = p.stdin.take().expect("failed to take stdin");
+    let stdin = p.stdin.take().expect("failed to take stdin");
+    // a websocket upgrade keeps stdin for incoming messages, rather than the request body
+    let (body_stdin, ws_stdin) = match on_upgrade {
+        Some(_) => (None, Some(stdin)),
+        None => (Some(stdin), None),
+    };
 
     tokio::spawn(async move {
         req_writer
@@ -239,28 +981,207 @@ async fn handler(
             .expect("failed to write request metadata");
         drop(req_writer);
 
-        let req_body = req_body.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+        let Some(mut stdin) = body_stdin else {
+            return;
+        };
+        // a command that reads stdin before it responds gets the body once the wait is up
+        if let Some(continue_rx) = continue_rx {
+            if let Ok(accepted) = tokio::time::timeout(continue_timeout, continue_rx).await {
+                if !accepted.unwrap_or(false) {
+                    return;
+                }
+            }
+        }
+        let req_body = req_body.map_err(std::io::Error::other);
         let mut req_body = tokio_util::io::StreamReader::new(req_body);
-        tokio::io::copy(&mut req_body, &mut stdin)
-            .await
-            .expect("streaming request body to stdin");
-    });
+        let mut body = std::io::Cursor::new(body_prefix).chain(&mut req_body);
+        // when decoding, the limits apply to the decoded body the command reads
+        let copied = match decode {
+            Some(encoding) => {
+                let decoder = compress::decoder(&encoding, &mut body).unwrap();
+                let limit = max_body_size
+                    .map_or(max_decompressed_size, |max| max.min(max_decompressed_size));
+                copy_limited(decoder, &mut stdin, limit).await
+            }
+            None => match max_body_size {
+                Some(limit) => copy_limited(&mut body, &mut stdin, limit).await,
+                None => tokio::io::copy(&mut body, &mut stdin).await.map(|_| true),
+            },
+        };
+        match &copied {
+            Ok(true) => {}
+            Ok(false) => body_cut_off(pid, stamp, "over the size limit"),
+            Err(e) => body_cut_off(pid, stamp, &e.to_string()),
+        }
+        drop(stdin);
 
-    let mut buf = String::new();
-    res_reader.read_to_string(&mut buf).await.unwrap();
-    drop(res_reader);
+        // trailers follow the body, which is abandoned if it was cut off
+        if let (Some(mut req_trailers), Ok(true)) = (req_trailers, copied) {
+            let mut req_body = req_body.into_inner().into_inner();
+            let trailers = hyper::body::HttpBody::trailers(&mut req_body)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            let mut json = Vec::new();
+            http_serde::header_map::serialize(
+                &trailers,
+                &mut serde_json::Serializer::new(&mut json),
+            )
+            .unwrap();
+            json.push(b'\n');
+            req_trailers
+                .write_all(&json)
+                .await
+                .or_else(|e| match e.kind() {
+                    std::io::ErrorKind::BrokenPipe => Ok(()),
+                    _ => Err(e),
+                })
+                .expect("failed to write request trailers");
+        }
+    });
 
-    let mut res_meta = if buf.is_empty() {
-        Response::default()
-    } else {
-        serde_json::from_str::<Response>(&buf).unwrap()
+    let mut stdout: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match pty {
+        Some(pty) => Box::new(pty),
+        None => Box::new(p.stdout.take().expect("failed to take stdout")),
     };
+    let (mut res_meta, prefix, stdout_closed) =
+        read_response_meta(res_reader, &mut stdout, args.wait_fd4_close).await;
+
+    // a command that's killed before it responds, say by a resource limit, gets an error status
+    // rather than an empty 200. Having closed both fd 4 and stdout, it has nothing left to send,
+    // so its exit is waited for
+    if res_meta == Response::default() && prefix.is_empty() && stdout_closed {
+        let mut shutdown = shutdown_rx.clone();
+        let exit = tokio::select! {
+            status = p.wait() => status.ok(),
+            _ = shutdown.wait_for(|shutdown| *shutdown) => None,
+        };
+        if let Some(signal) = exit.and_then(|status| status.signal()) {
+            let status = match nix::sys::signal::Signal::try_from(signal) {
+                Ok(
+                    nix::sys::signal::Signal::SIGKILL
+                    | nix::sys::signal::Signal::SIGXCPU
+                    | nix::sys::signal::Signal::SIGXFSZ,
+                ) => http::StatusCode::SERVICE_UNAVAILABLE,
+                _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            req_meta.response = Some(Response {
+                status: Some(status.as_u16()),
+                ..Default::default()
+            });
+            resources.log_request(&req_meta);
+            tokio::spawn(reap(p, resources));
+            let reason = match nix::sys::signal::Signal::try_from(signal) {
+                Ok(nix::sys::signal::Signal::SIGSYS) => ", a blocked system call",
+                _ => "",
+            };
+            return Dispatch::Response(
+                hyper::Response::builder()
+                    .status(status)
+                    .body(format!("command killed by {}{}\n", signal_name(signal), reason).into())
+                    .unwrap(),
+            );
+        }
+    }
+    let stdout = std::io::Cursor::new(prefix).chain(stdout);
+
+    if let Some(continue_tx) = continue_tx {
+        let _ = continue_tx.send(res_meta.status.is_none_or(|status| status < 400));
+    }
 
     req_meta.response = Some(res_meta.clone());
 
-    let status = res_meta.status.unwrap_or(200);
+    // an internal redirect re-dispatches the request without a round trip to the client. The
+    // request body has already been handed to this command, so the next hop gets an empty one
+    if let Some(path) = res_meta.internal {
+        resources.log_request(&req_meta);
+        let stamp = req_meta.stamp;
+        tokio::spawn(async move {
+            let p = stream_stdout(p, stdout, None, None, stamp, shutdown_rx).await;
+            reap(p, resources).await;
+        });
+
+        let mut next = hyper::Request::builder()
+            .method(req_meta.method)
+            .uri(path)
+            .version(version);
+        let headers = next.headers_mut().unwrap();
+        *headers = req_meta.headers;
+        // nothing in them describes the empty body
+        for name in [
+            http::header::CONTENT_LENGTH,
+            http::header::TRANSFER_ENCODING,
+            http::header::EXPECT,
+        ] {
+            headers.remove(name);
+        }
+        return match next.body(hyper::Body::empty()) {
+            Ok(next) => Dispatch::Internal(next),
+            Err(_) => Dispatch::Response(
+                hyper::Response::builder()
+                    .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            ),
+        };
+    }
+
+    let status = res_meta.status.unwrap_or(match res_meta.redirect {
+        Some(_) if res_meta.permanent.unwrap_or(false) => 301,
+        Some(_) => 302,
+        None => 200,
+    });
     res_meta.status = Some(status);
 
+    // a redirect target that can't be sent as a header is the command's error. Its output is
+    // drained, so it can run to completion
+    if let Some(error) = invalid_redirect(&res_meta) {
+        log_metadata_error(req_meta.stamp, &error);
+        req_meta.response = Some(Response {
+            status: Some(500),
+            ..Default::default()
+        });
+        resources.log_request(&req_meta);
+        let stamp = req_meta.stamp;
+        tokio::spawn(async move {
+            let p = stream_stdout(p, stdout, None, None, stamp, shutdown_rx).await;
+            reap(p, resources).await;
+        });
+        return Dispatch::Response(metadata_error());
+    }
+
+    // the command opted in to the websocket upgrade; stdin stays open for the bridge, which
+    // takes the place of the response body
+    if let (Some(true), Some(on_upgrade), Some(stdin)) = (res_meta.websocket, on_upgrade, ws_stdin)
+    {
+        let mut res = hyper::Response::builder()
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .header("upgrade", "websocket")
+            .header("connection", "upgrade")
+            .header(
+                "sec-websocket-accept",
+                websocket::accept_key(&req_meta.headers),
+            );
+        for (key, value) in res_meta.headers.into_iter().flatten() {
+            res = res.header(key, value);
+        }
+        req_meta.response.as_mut().unwrap().status = Some(101);
+        resources.log_request(&req_meta);
+
+        let frames = res_meta.websocket_frames.unwrap_or(false);
+        tokio::spawn(async move {
+            websocket::bridge(on_upgrade, stdin, stdout, frames).await;
+            terminate(&p);
+            reap(p, resources).await;
+        });
+
+        return Dispatch::Response(res.body(hyper::Body::empty()).unwrap());
+    }
+
+    let subscribe = res_meta.subscribe.filter(|_| args.topics);
+    let sse = res_meta.sse.unwrap_or(args.sse) || subscribe.is_some();
+    let keep_alive = std::time::Duration::from_secs(args.sse_keep_alive);
     let mut res = hyper::Response::builder().status(status);
     {
         let res_headers = res.headers_mut().unwrap();
@@ -273,190 +1194,2490 @@ async fn handler(
             }
         }
 
-        if !res_headers.contains_key("content-type") {
+        if let Some(location) = res_meta.redirect {
+            res_headers.insert(
+                "location",
+                http::header::HeaderValue::from_bytes(location.as_bytes()).unwrap(),
+            );
+        }
+
+        if sse {
+            if !res_headers.contains_key("content-type") {
+                res_headers.insert("content-type", "text/event-stream".parse().unwrap());
+            }
+            if !res_headers.contains_key("cache-control") {
+                res_headers.insert("cache-control", "no-cache".parse().unwrap());
+            }
+        }
+
+        if res_meta.file.is_none() && !res_headers.contains_key("content-type") {
             res_headers.insert("content-type", "text/plain".parse().unwrap());
         }
     }
 
-    let (mut sender, body) = hyper::Body::channel();
-    let stdout = p.stdout.take().expect("failed to take stdout");
-    tokio::spawn(async move {
-        let mut stdout = tokio::io::BufReader::new(stdout);
-        let mut buf = [0; 4096];
-
-        loop {
-            tokio::select! {
-                Ok(n) = stdout.read(&mut buf[..]) => {
-                    if n == 0 {
-                        break; // EOF reached
-                    }
+    // files and topic subscriptions are passed through as is
+    let compressible = args.compress
+        && subscribe.is_none()
+        && res_meta.file.is_none()
+        && req_meta.method != http::Method::HEAD
+        && !matches!(status, 204 | 304)
+        && compress::compressible(
+            res.headers_ref().unwrap(),
+            res_meta.body.as_ref().map(|body| body.len() as u64),
+            args.compress_min_size,
+        );
+    let encoding = compressible
+        .then(|| compress::choose(&req_meta.headers))
+        .flatten();
+    if compressible {
+        // the response depends on accept-encoding, even when it goes out uncompressed
+        let res_headers = res.headers_mut().unwrap();
+        res_headers.append("vary", "accept-encoding".parse().unwrap());
+    }
+    if let Some(encoding) = encoding {
+        let res_headers = res.headers_mut().unwrap();
+        res_headers.insert("content-encoding", encoding.as_str().parse().unwrap());
+        res_headers.remove("content-length");
+    }
 
-                    if sender.send_data(buf[..n].to_vec().into()).await.is_err() {
-                        break;
-                    }
-                }
-                status = shutdown_rx.changed() => {
-                    if status.is_err() {
-                        break;
-                    }
-                    if *shutdown_rx.borrow() {
-                        break;
-                    }
-                },
+    // a topic subscription, file or inline body replaces stdout, which is still drained so the
+    // command can run to completion
+    let (sender, res) = match (subscribe, res_meta.file, res_meta.body) {
+        (Some(topic), _, _) => {
+            let (sender, body) = hyper::Body::channel();
+            let last_event_id = req_meta
+                .last_event_id
+                .as_ref()
+                .and_then(|id| id.parse().ok());
+            let subscription = state.topics.subscribe(&topic, last_event_id);
+            tokio::spawn(topics::stream(
+                subscription,
+                sender,
+                keep_alive,
+                shutdown_rx.clone(),
+            ));
+            (None, res.body(body).unwrap())
+        }
+        (None, Some(path), _) => {
+            // relative paths are the command's, so they start from its working directory
+            let path = match &args.cwd {
+                Some(cwd) => cwd.join(path),
+                None => path,
+            };
+            (
+                None,
+                serve_file(res, status, &path, &args.file_root, &req_meta).await,
+            )
+        }
+        (None, None, Some(inline)) => match encoding {
+            Some(encoding) => {
+                let encoded = compress::encode_all(inline.as_bytes(), encoding).await;
+                (None, res.body(encoded.into()).unwrap())
             }
+            None => (None, res.body(inline.into()).unwrap()),
+        },
+        (None, None, None) => {
+            let (sender, body) = hyper::Body::channel();
+            (Some(sender), res.body(body).unwrap())
         }
+    };
 
-        let pid = nix::unistd::Pid::from_raw(p.id().unwrap() as i32);
-        nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM).unwrap();
+    let mut stdout: Box<dyn tokio::io::AsyncRead + Unpin + Send> = Box::new(stdout);
+    if sender.is_some() {
+        if sse {
+            stdout = Box::new(sse::frames(stdout, keep_alive));
+        }
+        if let Some(encoding) = encoding {
+            stdout = Box::new(compress::encode(stdout, encoding));
+        }
+    }
+    resources.log_request(&req_meta);
+    let stamp = req_meta.stamp;
+    tokio::spawn(async move {
+        let p = stream_stdout(p, stdout, sender, res_trailers, stamp, shutdown_rx).await;
+        reap(p, resources).await;
     });
 
-    let ret = res.body(body).unwrap();
-    println!("{}", serde_json::to_string(&req_meta).unwrap());
-    ret
+    Dispatch::Response(res)
 }
 
-fn configure_tls(pem: PathBuf) -> tokio_rustls::TlsAcceptor {
-    let pem = std::fs::File::open(pem).unwrap();
-    let mut pem = std::io::BufReader::new(pem);
+/// Builds the response to a request handled by a worker. Workers support the status, headers,
+/// redirect and inline body response metadata.
+fn worker_response(res_meta: &Response, body: hyper::Body) -> hyper::Response<hyper::Body> {
+    let status = res_meta.status.unwrap_or(match res_meta.redirect {
+        Some(_) if res_meta.permanent.unwrap_or(false) => 301,
+        Some(_) => 302,
+        None => 200,
+    });
+    let mut res = hyper::Response::builder().status(status);
+    let res_headers = res.headers_mut().unwrap();
+    for (key, value) in res_meta.headers.iter().flatten() {
+        res_headers.insert(
+            http::header::HeaderName::from_bytes(key.as_bytes()).unwrap(),
+            http::header::HeaderValue::from_bytes(value.as_bytes()).unwrap(),
+        );
+    }
+    if let Some(location) = &res_meta.redirect {
+        res_headers.insert(
+            "location",
+            http::header::HeaderValue::from_bytes(location.as_bytes()).unwrap(),
+        );
+    }
+    if !res_headers.contains_key("content-type") {
+        res_headers.insert("content-type", "text/plain".parse().unwrap());
+    }
 
-    let items = rustls_pemfile::read_all(&mut pem).unwrap();
+    // an inline body stands in for the worker's frames, which are still read to keep it in step
+    match &res_meta.body {
+        Some(inline) => res.body(inline.clone().into()).unwrap(),
+        None => res.body(body).unwrap(),
+    }
+}
 
-    let certs: Vec<rustls::Certificate> = items
-        .iter()
-        .filter_map(|item| match item {
-            rustls_pemfile::Item::X509Certificate(cert) => Some(rustls::Certificate(cert.to_vec())),
-            _ => None,
-        })
-        .collect();
+/// The body formats `--parse-body` understands.
+#[derive(Clone, Copy)]
+enum BodyFormat {
+    Urlencoded,
+    Json,
+}
 
-    let key = items
-        .into_iter()
-        .find_map(|item| match item {
-            rustls_pemfile::Item::RSAKey(key) => Some(rustls::PrivateKey(key)),
-            rustls_pemfile::Item::PKCS8Key(key) => Some(rustls::PrivateKey(key)),
-            rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
-            rustls_pemfile::Item::X509Certificate(_) => None,
-            _ => todo!(),
-        })
-        .unwrap();
+/// The format of a body, from its content type, if it's one that can be parsed.
+fn body_format(headers: &http::HeaderMap) -> Option<BodyFormat> {
+    let content_type = headers.get("content-type")?.to_str().ok()?;
+    let mime: mime_guess::mime::Mime = content_type.parse().ok()?;
+    match (mime.type_(), mime.subtype(), mime.suffix()) {
+        (mime_guess::mime::APPLICATION, mime_guess::mime::WWW_FORM_URLENCODED, _) => {
+            Some(BodyFormat::Urlencoded)
+        }
+        (mime_guess::mime::APPLICATION, mime_guess::mime::JSON, _)
+        | (mime_guess::mime::APPLICATION, _, Some(mime_guess::mime::JSON)) => {
+            Some(BodyFormat::Json)
+        }
+        _ => None,
+    }
+}
 
-    let mut config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .unwrap();
-    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+/// Parses a urlencoded or JSON body. A body that fails to parse is left for the command to read
+/// from stdin.
+fn parse_body(format: BodyFormat, body: &[u8]) -> Option<serde_json::Value> {
+    match format {
+        BodyFormat::Urlencoded => {
+            let fields: serde_json::Map<String, serde_json::Value> =
+                url::form_urlencoded::parse(body)
+                    .into_owned()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect();
+            Some(fields.into())
+        }
+        BodyFormat::Json => serde_json::from_slice(body).ok(),
+    }
+}
 
-    tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))
+/// Reads the body's data until it ends or there's more than `limit` bytes of it, returning
+/// what was read and whether that's all of it. Trailers are left to be read from the body.
+async fn read_prefix(body: &mut hyper::Body, limit: u64) -> hyper::Result<(Vec<u8>, bool)> {
+    let mut prefix = Vec::new();
+    while let Some(data) = hyper::body::HttpBody::data(body).await {
+        prefix.extend_from_slice(&data?);
+        if prefix.len() as u64 > limit {
+            return Ok((prefix, false));
+        }
+    }
+    Ok((prefix, true))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use indoc::indoc;
-    use pretty_assertions::assert_eq;
+/// Copies up to `limit` bytes, returning false if the reader had more to give.
+async fn copy_limited(
+    reader: impl tokio::io::AsyncRead + Unpin,
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    limit: u64,
+) -> std::io::Result<bool> {
+    let mut reader = reader.take(limit);
+    tokio::io::copy(&mut reader, writer).await?;
+    let mut reader = reader.into_inner();
+    Ok(reader.read(&mut [0]).await? == 0)
+}
 
-    #[tokio::test]
-    async fn handler_get() {
-        let (_tx, rx) = tokio::sync::watch::channel(false);
-        let req = hyper::Request::get("https://api.cross.stream/")
-            .body(hyper::Body::empty())
-            .unwrap();
-        let resp = handler(
-            rx,
-            req,
-            None,
-            &None,
-            &"echo".into(),
-            &vec!["hello world".into()],
-        )
-        .await;
-        assert_eq!(resp.status(), hyper::StatusCode::OK);
-        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
-        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
-        let body = std::str::from_utf8(&body).unwrap();
-        assert_eq!(body, "hello world\n");
+/// Logs that a request body was cut off, by a limit or an error such as a bad encoding, and
+/// lets the command know with SIGURG. Its default action is to ignore it, so a command that
+/// doesn't trap it just sees stdin close early.
+fn body_cut_off(pid: Option<u32>, stamp: scru128::Scru128Id, error: &str) {
+    println!(
+        "{}",
+        json!({
+            "stamp": scru128::new(),
+            "message": "body",
+            "request": stamp,
+            "error": error,
+        })
+    );
+    if let Some(pid) = pid {
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGURG);
     }
+}
 
-    #[tokio::test]
-    async fn handler_post() {
-        let (_tx, rx) = tokio::sync::watch::channel(false);
-        let req = hyper::Request::post("https://api.cross.stream/")
-            .header("Last-Event-ID", 5)
-            .body("zebody".into())
-            .unwrap();
-        let resp = handler(rx, req, None, &None, &"cat".into(), &vec![]).await;
-        assert_eq!(resp.status(), hyper::StatusCode::OK);
+/// Reads the response metadata from fd 4. Unless `wait_close` is set, this returns as soon as
+/// fd 4 has a complete response document, or once stdout has output, in which case the output
+/// read so far is returned to be sent ahead of the rest of stdout. Also returns whether stdout
+/// is known to have closed without output.
+async fn read_response_meta(
+    mut res_reader: tokio_pipe::PipeRead,
+    stdout: &mut (impl tokio::io::AsyncRead + Unpin),
+    wait_close: bool,
+) -> (Response, Vec<u8>, bool) {
+    // fd 4 carries a sequence of JSON documents, the first of which describes the response
+    let mut meta = None;
+    let mut buf = Vec::new();
+    let mut offset = 0;
+    let mut chunk = [0; 4096];
+    let mut prefix = [0; 4096];
+    let mut stdout_closed = false;
+    let mut closed = false;
+
+    let n = loop {
+        tokio::select! {
+            // fd 4 is checked first, so metadata written ahead of output is always seen first
+            biased;
+
+            n = res_reader.read(&mut chunk), if !closed => {
+                let n = n.unwrap();
+                if n == 0 {
+                    closed = true;
+                    // with nothing described, the response starts at the first output instead,
+                    // or once stdout closes
+                    if !buf.is_empty() || stdout_closed {
+                        break 0;
+                    }
+                    continue;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+
+                let mut described = false;
+                let mut docs =
+                    serde_json::Deserializer::from_slice(&buf[offset..]).into_iter::<Response>();
+                while !described || wait_close {
+                    match docs.next() {
+                        Some(Ok(doc)) => {
+                            meta.get_or_insert(doc);
+                            described = true;
+                        }
+                        // the rest of the document hasn't been written yet
+                        Some(Err(e)) if e.is_eof() => break,
+                        Some(Err(e)) => panic!("invalid response metadata: {}", e),
+                        None => break,
+                    }
+                }
+                offset += docs.byte_offset();
+
+                if described && !wait_close {
+                    break 0;
+                }
+            }
+
+            n = stdout.read(&mut prefix), if (!wait_close || closed) && !stdout_closed => {
+                let n = n.unwrap();
+                if n != 0 {
+                    break n;
+                }
+                stdout_closed = true;
+                if closed {
+                    break 0;
+                }
+            }
+        }
+    };
+
+    // keep draining fd 4, so a command writing to it later doesn't see a broken pipe
+    if !closed {
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut res_reader, &mut tokio::io::sink()).await;
+        });
+    }
+
+    (
+        meta.unwrap_or_default(),
+        prefix[..n].to_vec(),
+        stdout_closed,
+    )
+}
+
+/// Streams the command's stdout to `sender`, or discards it when there's no sender, then
+/// terminates the command once stdout closes or the connection goes away. Trailers written by
+/// the command are sent after stdout closes; ones that can't be parsed are logged and dropped.
+async fn stream_stdout(
+    p: tokio::process::Child,
+    stdout: impl tokio::io::AsyncRead + Unpin,
+    mut sender: Option<hyper::body::Sender>,
+    trailers: Option<tokio_pipe::PipeRead>,
+    stamp: scru128::Scru128Id,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> tokio::process::Child {
+    let mut stdout = tokio::io::BufReader::new(stdout);
+    let mut buf = [0; 4096];
+    let mut eof = false;
+
+    loop {
+        tokio::select! {
+            Ok(n) = stdout.read(&mut buf[..]) => {
+                if n == 0 {
+                    eof = true;
+                    break; // EOF reached
+                }
+
+                if let Some(sender) = sender.as_mut() {
+                    if sender.send_data(buf[..n].to_vec().into()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            status = shutdown_rx.changed() => {
+                if status.is_err() {
+                    break;
+                }
+                if *shutdown_rx.borrow() {
+                    break;
+                }
+            },
+        }
+    }
+
+    if let (true, Some(sender), Some(mut trailers)) = (eof, sender.as_mut(), trailers) {
+        let mut buf = String::new();
+        let fields = match trailers.read_to_string(&mut buf).await {
+            Ok(_) if buf.trim().is_empty() => Ok(HashMap::new()),
+            Ok(_) => {
+                serde_json::from_str::<HashMap<String, String>>(&buf).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let fields = fields.unwrap_or_else(|e| {
+            log_trailer_error(stamp, None, &e);
+            HashMap::new()
+        });
+        let mut map = http::header::HeaderMap::new();
+        for (key, value) in fields {
+            let name = http::header::HeaderName::from_bytes(key.as_bytes());
+            let value = http::header::HeaderValue::from_bytes(value.as_bytes());
+            match (name, value) {
+                (Ok(name), Ok(value)) => {
+                    map.insert(name, value);
+                }
+                (Err(e), _) => log_trailer_error(stamp, Some(&key), &e.to_string()),
+                (_, Err(e)) => log_trailer_error(stamp, Some(&key), &e.to_string()),
+            }
+        }
+        if !map.is_empty() {
+            let _ = sender.send_trailers(map).await;
+        }
+    }
+
+    terminate(&p);
+    p
+}
+
+/// Logs a response trailer from fd 5 that was dropped.
+/// Checks that a redirect's target can be sent as the location header.
+fn invalid_redirect(res_meta: &Response) -> Option<String> {
+    let location = res_meta.redirect.as_ref()?;
+    let error = http::header::HeaderValue::from_bytes(location.as_bytes()).err()?;
+    Some(format!("redirect {:?}: {}", location, error))
+}
+
+/// Logs response metadata that can't be acted on, which is answered with `metadata_error`.
+fn log_metadata_error(stamp: scru128::Scru128Id, error: &str) {
+    println!(
+        "{}",
+        json!({
+            "stamp": scru128::new(),
+            "message": "metadata",
+            "request": stamp,
+            "error": error,
+        })
+    );
+}
+
+fn metadata_error() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+        .body("invalid response metadata\n".into())
+        .unwrap()
+}
+
+fn log_trailer_error(stamp: scru128::Scru128Id, name: Option<&str>, error: &str) {
+    println!(
+        "{}",
+        json!({
+            "stamp": scru128::new(),
+            "message": "trailers",
+            "request": stamp,
+            "name": name,
+            "error": error,
+        })
+    );
+}
+
+/// Logs a command that couldn't be spawned, for the request with `stamp` if it was spawned for one.
+fn log_spawn_error(stamp: Option<scru128::Scru128Id>, error: &std::io::Error) {
+    println!(
+        "{}",
+        json!({
+            "stamp": scru128::new(),
+            "message": "spawn",
+            "request": stamp,
+            "error": error.to_string(),
+        })
+    );
+}
+
+/// A spawned command, along with the parent's ends of its fds.
+struct Spawned {
+    p: tokio::process::Child,
+    /// With --pty, the command's stdout
+    pty: Option<pty::Pty>,
+    req_writer: tokio_pipe::PipeWrite,
+    res_reader: tokio_pipe::PipeRead,
+    res_trailers: Option<tokio_pipe::PipeRead>,
+    req_trailers: Option<tokio_pipe::PipeWrite>,
+    publish: Option<tokio_pipe::PipeRead>,
+    cgroup: Option<cgroup::Cgroup>,
+}
+
+/// Spawns the command with its fds, the request's uploads in `spool`, and whatever `launch`
+/// confines it with. The steps registered with `pre_exec` run in the child between fork and exec,
+/// in the order they're registered, so each of them sticks to async-signal-safe calls.
+fn spawn(args: &Args, launch: &Launch, spool: Option<&Path>) -> std::io::Result<Spawned> {
+    let (req_reader, req_writer) = tokio_pipe::pipe().unwrap();
+    let (res_reader, res_writer) = tokio_pipe::pipe().unwrap();
+    let trailers = args
+        .trailers
+        .then(|| (tokio_pipe::pipe().unwrap(), tokio_pipe::pipe().unwrap()));
+
+    let mut fd_mappings = vec![
+        FdMapping {
+            parent_fd: req_reader.as_raw_fd(),
+            child_fd: 3,
+        },
+        FdMapping {
+            parent_fd: res_writer.as_raw_fd(),
+            child_fd: 4,
+        },
+    ];
+    let publish = args.topics.then(|| tokio_pipe::pipe().unwrap());
+    if let Some((_, publish_writer)) = &publish {
+        fd_mappings.push(FdMapping {
+            parent_fd: publish_writer.as_raw_fd(),
+            child_fd: 7,
+        });
+    }
+    if let Some(((_, res_trailers_writer), (req_trailers_reader, _))) = &trailers {
+        fd_mappings.push(FdMapping {
+            parent_fd: res_trailers_writer.as_raw_fd(),
+            child_fd: 5,
+        });
+        fd_mappings.push(FdMapping {
+            parent_fd: req_trailers_reader.as_raw_fd(),
+            child_fd: 6,
+        });
+    }
+    // tokio_pipe makes both ends non-blocking. The command's ends are its own open file
+    // descriptions, so they're handed over blocking, or a read of fd 3 could fail with EAGAIN
+    for mapping in &fd_mappings {
+        set_blocking(mapping.parent_fd);
+    }
+
+    let (pty, stdout) = match args.pty {
+        true => {
+            let (pty, tty) = pty::open(args.pty_size, args.pty_cooked)?;
+            (Some(pty), std::process::Stdio::from(tty))
+        }
+        false => (None, std::process::Stdio::piped()),
+    };
+
+    let mut command = tokio::process::Command::new(&args.command);
+    launch.environment.apply(&mut command);
+    command
+        .args(&args.args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(stdout)
+        .process_group(0)
+        .fd_mappings(fd_mappings)
+        .unwrap();
+
+    // worked out ahead of the cgroup, so there's nothing to clean up if they fail
+    let extra = match (&launch.sandbox, spool) {
+        (Some(sandbox), Some(spool)) => Some(sandbox.extra(spool)?),
+        _ => None,
+    };
+    let mut ruleset = match &launch.landlock {
+        Some(rules) => Some(rules.ruleset().map_err(std::io::Error::other)?),
+        None => None,
+    };
+
+    let cgroup = match &args.cgroup {
+        Some(root) => Some(cgroup::Cgroup::create(
+            root,
+            args.cgroup_memory_max,
+            args.cgroup_cpu_max.as_deref(),
+        )?),
+        None => None,
+    };
+    if let Some(cgroup) = &cgroup {
+        let procs = cgroup.procs();
+        unsafe {
+            command.pre_exec(move || cgroup::enter(&procs));
+        }
+    }
+
+    // after the cgroup, whose files aren't visible from inside, and before giving up root. The
+    // request's uploads are bound in, as the sandbox has a /tmp of its own
+    if let Some(sandbox) = launch.sandbox.clone() {
+        unsafe {
+            command.pre_exec(move || sandbox.enter(extra.as_ref()));
+        }
+    }
+
+    use nix::sys::resource::Resource;
+    let rlimits: Vec<(Resource, u64)> = [
+        (Resource::RLIMIT_AS, args.rlimit_as),
+        (Resource::RLIMIT_CPU, args.rlimit_cpu),
+        (Resource::RLIMIT_NOFILE, args.rlimit_nofile),
+        (Resource::RLIMIT_NPROC, args.rlimit_nproc),
+        (Resource::RLIMIT_FSIZE, args.rlimit_fsize),
+    ]
+    .into_iter()
+    .filter_map(|(resource, limit)| Some((resource, limit?)))
+    .collect();
+    if !rlimits.is_empty() {
+        // after the sandbox, so the processes it leaves waiting aren't held to the limits
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in &rlimits {
+                    // a second's grace past the CPU limit lets SIGXCPU be handled before SIGKILL
+                    let hard = match resource {
+                        Resource::RLIMIT_CPU => limit + 1,
+                        _ => *limit,
+                    };
+                    nix::sys::resource::setrlimit(*resource, *limit, hard)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // with --drop-privileges, http-sh is already running as the command's user, except for the
+    // commands --prespawn starts before it has dropped them
+    let dropped = args.drop_privileges && !nix::unistd::Uid::effective().is_root();
+    if let Some(identity) = launch.identity.clone().filter(|_| !dropped) {
+        unsafe {
+            command.pre_exec(move || Ok(privileges::assume(&identity)?));
+        }
+    }
+
+    // last, so the steps above aren't held to the rules
+    if ruleset.is_some() {
+        unsafe {
+            command.pre_exec(move || confine::restrict(ruleset.take().unwrap()));
+        }
+    }
+    if let Some(filter) = launch.seccomp.clone() {
+        unsafe {
+            command.pre_exec(move || confine::apply(&filter));
+        }
+    }
+
+    let p = match command.spawn() {
+        Ok(p) => p,
+        Err(e) => {
+            if let Some(cgroup) = cgroup {
+                cgroup.remove();
+            }
+            return Err(e);
+        }
+    };
+
+    drop(req_reader);
+    drop(res_writer);
+    let (res_trailers, req_trailers) = match trailers {
+        Some(((reader, _), (_, writer))) => (Some(reader), Some(writer)),
+        None => (None, None),
+    };
+    Ok(Spawned {
+        p,
+        pty,
+        req_writer,
+        res_reader,
+        res_trailers,
+        req_trailers,
+        publish: publish.map(|(reader, _)| reader),
+        cgroup,
+    })
+}
+
+/// Held on behalf of the command until it exits.
+struct Resources {
+    /// The request's stamp, to tie the exit to its request in the log
+    stamp: scru128::Scru128Id,
+    spool: Option<tempfile::TempDir>,
+    permit: Option<Arc<tokio::sync::OwnedSemaphorePermit>>,
+    cgroup: Option<cgroup::Cgroup>,
+    /// With a cgroup, the request, to be logged along with the command's usage once it exits
+    request: Option<Request>,
+}
+
+impl Resources {
+    /// Logs the request, or with a cgroup, holds on to it until the command's usage is known.
+    fn log_request(&mut self, req_meta: &Request) {
+        match self.cgroup {
+            Some(_) => self.request = Some(req_meta.clone()),
+            None => println!("{}", serde_json::to_string(req_meta).unwrap()),
+        }
+    }
+}
+
+/// Waits for the command to exit, then releases what it held.
+async fn reap(mut p: tokio::process::Child, resources: Resources) {
+    let pid = p.id();
+    let status = p.wait().await.ok();
+    if let Some(cgroup) = resources.cgroup {
+        let usage = cgroup.finish().await;
+        if let Some(mut request) = resources.request {
+            request.cpu_usec = usage.cpu_usec;
+            request.memory_peak = usage.memory_peak;
+            println!("{}", serde_json::to_string(&request).unwrap());
+        }
+    }
+    let log = json!({
+        "stamp": scru128::new(),
+        "message": "exit",
+        "request": resources.stamp,
+        "pid": pid,
+        "code": status.and_then(|status| status.code()),
+        "signal": status.and_then(|status| status.signal()).map(signal_name),
+    });
+    println!("{}", log);
+    drop(resources.spool);
+    drop(resources.permit);
+}
+
+fn set_blocking(fd: std::os::fd::RawFd) {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL).unwrap());
+    fcntl(fd, FcntlArg::F_SETFL(flags - OFlag::O_NONBLOCK)).unwrap();
+}
+
+fn signal_name(signal: i32) -> String {
+    match nix::sys::signal::Signal::try_from(signal) {
+        Ok(signal) => signal.as_str().to_string(),
+        Err(_) => signal.to_string(),
+    }
+}
+
+/// Signals the command's process group, so anything it started goes along with it.
+fn terminate(p: &tokio::process::Child) {
+    // a command that has already been waited on has nothing left to signal
+    let Some(pid) = p.id() else {
+        return;
+    };
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    // ESRCH: nothing is left in the group to signal
+    match nix::sys::signal::killpg(pid, nix::sys::signal::Signal::SIGTERM) {
+        Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+        Err(e) => println!(
+            "{}",
+            json!({
+                "stamp": scru128::new(),
+                "message": "terminate",
+                "pid": pid.as_raw(),
+                "error": e.to_string(),
+            })
+        ),
+    }
+}
+
+/// Serves `path` as the response body, honoring conditional and range request headers. Headers
+/// set by the command take precedence over those derived from the file.
+async fn serve_file(
+    res: http::response::Builder,
+    status: u16,
+    path: &std::path::Path,
+    roots: &[PathBuf],
+    req_meta: &Request,
+) -> hyper::Response<hyper::Body> {
+    let mut ret = match open_file(path, roots, req_meta).await {
+        Ok(ret) => ret,
+        Err(status) => hyper::Response::builder()
+            .status(status)
+            .body(hyper::Body::empty())
+            .unwrap(),
+    };
+
+    // the command's status takes the place of a plain 200, but not of a 304 or a range response
+    if ret.status() == http::StatusCode::OK {
+        *ret.status_mut() = http::StatusCode::from_u16(status).unwrap();
+    }
+    if let Some(headers) = res.headers_ref() {
+        for (key, value) in headers {
+            ret.headers_mut().insert(key, value.clone());
+        }
+    }
+    ret
+}
+
+/// Opens the file for `serve_file`, or returns the status to answer with instead.
+async fn open_file(
+    path: &std::path::Path,
+    roots: &[PathBuf],
+    req_meta: &Request,
+) -> Result<hyper::Response<hyper::Body>, http::StatusCode> {
+    let path = tokio::fs::canonicalize(path)
+        .await
+        .map_err(|_| http::StatusCode::NOT_FOUND)?;
+
+    if !roots.is_empty() {
+        let mut allowed = false;
+        for root in roots {
+            if let Ok(root) = tokio::fs::canonicalize(root).await {
+                allowed |= path.starts_with(root);
+            }
+        }
+        if !allowed {
+            return Err(http::StatusCode::FORBIDDEN);
+        }
+    }
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return Err(http::StatusCode::FORBIDDEN)
+        }
+        Err(_) => return Err(http::StatusCode::NOT_FOUND),
+    };
+    let metadata = file.metadata().await.unwrap();
+    if !metadata.is_file() {
+        return Err(http::StatusCode::NOT_FOUND);
+    }
+
+    let content_type = mime_guess::from_path(&path)
+        .first_or_octet_stream()
+        .to_string();
+    Ok(hyper_staticfile::FileResponseBuilder::new()
+        .request_parts(&req_meta.method, &req_meta.headers)
+        .build(file, metadata, content_type)
+        .unwrap())
+}
+
+fn configure_tls(pem: PathBuf) -> tokio_rustls::TlsAcceptor {
+    let pem = std::fs::File::open(pem).unwrap();
+    let mut pem = std::io::BufReader::new(pem);
+
+    let items = rustls_pemfile::read_all(&mut pem).unwrap();
+
+    let certs: Vec<rustls::Certificate> = items
+        .iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(cert) => Some(rustls::Certificate(cert.to_vec())),
+            _ => None,
+        })
+        .collect();
+
+    let key = items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) => Some(rustls::PrivateKey(key)),
+            rustls_pemfile::Item::PKCS8Key(key) => Some(rustls::PrivateKey(key)),
+            rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            rustls_pemfile::Item::X509Certificate(_) => None,
+            _ => todo!(),
+        })
+        .unwrap();
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    /// State whose commands are spawned with `launch`.
+    fn launched(launch: Launch) -> State {
+        State {
+            launch: Arc::new(launch),
+            ..Default::default()
+        }
+    }
+
+    fn command(command: &str, args: &[&str]) -> Args {
+        Args::parse_from(["http-sh", "127.0.0.1:0", "--", command].iter().chain(args))
+    }
+
+    #[tokio::test]
+    async fn handler_get() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &command("echo", &["hello world"]),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "hello world\n");
+    }
+
+    #[tokio::test]
+    async fn handler_post() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("Last-Event-ID", 5)
+            .body("zebody".into())
+            .unwrap();
+        let resp = handler(rx, req, None, &command("cat", &[]), &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "zebody");
+    }
+
+    #[tokio::test]
+    async fn handler_response_empty() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    cat > /dev/null
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
         assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
         let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
-        let body = std::str::from_utf8(&body).unwrap();
-        assert_eq!(body, "zebody");
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "");
+    }
+
+    #[tokio::test]
+    async fn handler_response_meta() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/notfound")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    echo '{"status":404,"headers":{"content-type":"text/markdown"}}' >&4
+                    echo '# Not Found'
+                    jq -r .path <&3
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/markdown");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(
+            body,
+            indoc! {r#"
+            # Not Found
+            /notfound
+            "#}
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_response_redirect() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/old")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    echo '{"redirect":"/new","permanent":true}' >&4
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers().get("location").unwrap(), "/new");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        // a target that can't be a header is an error, rather than a panic
+        let req = hyper::Request::get("https://api.cross.stream/old")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command(
+            "sh",
+            &["-c", r#"printf '%s' '{"redirect":"/new\r\nx: y"}' >&4"#],
+        );
+        let resp = handler(rx, req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn handler_response_body() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    echo '{"status":403,"body":"forbidden"}' >&4
+                    echo ignored
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "forbidden");
+    }
+
+    #[tokio::test]
+    async fn handler_response_file() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        let filename = d.path().join("index.html");
+        std::fs::write(&filename, "hello world").unwrap();
+        let script = format!(r#"echo '{{"file":"{}"}}' >&4"#, filename.display());
+
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &command("sh", &["-c", &script]),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/html");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "hello world");
+
+        // a relative path starts from --cwd
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                cwd: Some(d.path().to_path_buf()),
+                ..command("sh", &["-c", r#"echo '{"file":"index.html"}' >&4"#])
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("range", "bytes=0-4")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &command("sh", &["-c", &script]),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "hello");
+
+        // file outside of the allowed roots
+        let root = tempfile::tempdir().unwrap();
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                file_root: vec![root.path().to_path_buf()],
+                ..command("sh", &["-c", &script])
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+
+        // the command's status and headers apply to the file, and to an error in its place
+        let script = format!(
+            r#"echo '{{"file":"{}","status":404,"headers":{{"x-page":"missing"}}}}' >&4"#,
+            filename.display()
+        );
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &command("sh", &["-c", &script]),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get("x-page").unwrap(), "missing");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                file_root: vec![root.path().to_path_buf()],
+                ..command("sh", &["-c", &script])
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers().get("x-page").unwrap(), "missing");
+    }
+
+    #[tokio::test]
+    async fn handler_response_internal() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        std::fs::write(d.path().join("login.html"), "please log in").unwrap();
+
+        let req = hyper::Request::get("https://api.cross.stream/private")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                static_path: Some(d.path().to_path_buf()),
+                ..command(
+                    "sh",
+                    &[
+                        "-c",
+                        r#"
+                        echo '{"internal":"/login.html"}' >&4
+                        "#,
+                    ],
+                )
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/html");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body, "please log in");
+
+        // a command that always redirects to itself is cut off
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                max_internal_redirects: 2,
+                ..command("sh", &["-c", r#"echo '{"internal":"/"}' >&4"#])
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the next hop's headers don't describe the body it no longer has
+        let req = hyper::Request::post("https://api.cross.stream/upload")
+            .header("content-length", "5")
+            .header("expect", "100-continue")
+            .header("x-kept", "yes")
+            .body(hyper::Body::from("hello"))
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    req=$(cat <&3)
+                    case "$(echo "$req" | jq -r .path)" in
+                    /upload) echo '{"internal":"/next"}' >&4 ;;
+                    *) echo "$req" | jq -c '.headers | [.["content-length"], .expect, .["x-kept"]]' ;;
+                    esac
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "[null,null,\"yes\"]\n");
+    }
+
+    #[tokio::test]
+    async fn handler_trailers() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("zebody".into()).await.unwrap();
+            let mut trailers = http::header::HeaderMap::new();
+            trailers.insert("x-request-checksum", "123".parse().unwrap());
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .body(body)
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                trailers: true,
+                ..command(
+                    "sh",
+                    &[
+                        "-c",
+                        r#"
+                        cat
+                        jq -c . <&6
+                        echo '{"x-checksum":"abc"}' >&5
+                        "#,
+                    ],
+                )
+            },
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let mut body = resp.into_body();
+        let mut got = Vec::new();
+        while let Some(data) = hyper::body::HttpBody::data(&mut body).await {
+            got.extend_from_slice(&data.unwrap());
+        }
+        assert_eq!(
+            std::str::from_utf8(&got).unwrap(),
+            "zebody{\"x-request-checksum\":\"123\"}\n"
+        );
+        let trailers = hyper::body::HttpBody::trailers(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+
+        // trailers that aren't valid header fields are dropped, leaving the rest
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx.clone(),
+            req,
+            None,
+            &Args {
+                trailers: true,
+                ..command(
+                    "sh",
+                    &["-c", r#"echo '{"x-checksum":"abc","bad name":"x"}' >&5"#],
+                )
+            },
+            &State::default(),
+        )
+        .await;
+        let mut body = resp.into_body();
+        while hyper::body::HttpBody::data(&mut body).await.is_some() {}
+        let trailers = hyper::body::HttpBody::trailers(&mut body)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+
+        // as are trailers that aren't JSON, and the body still ends
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &Args {
+                trailers: true,
+                ..command("sh", &["-c", "echo hi; echo nope >&5"])
+            },
+            &State::default(),
+        )
+        .await;
+        let mut body = resp.into_body();
+        let data = hyper::body::HttpBody::data(&mut body).await.unwrap();
+        assert_eq!(data.unwrap(), "hi\n");
+        assert!(hyper::body::HttpBody::data(&mut body).await.is_none());
+        let trailers = hyper::body::HttpBody::trailers(&mut body).await.unwrap();
+        assert!(trailers.is_none());
+    }
+
+    #[tokio::test]
+    async fn handler_response_start() {
+        let timeout = std::time::Duration::from_secs(5);
+
+        // starts on the first complete document, while fd 4 is still open
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command(
+            "sh",
+            &[
+                "-c",
+                r#"
+                echo '{"status":201}' >&4
+                echo first
+                sleep 30
+                "#,
+            ],
+        );
+        let resp = tokio::time::timeout(timeout, handler(rx, req, None, &args, &State::default()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::CREATED);
+        let mut body = resp.into_body();
+        let chunk = tokio::time::timeout(timeout, hyper::body::HttpBody::data(&mut body))
+            .await
+            .unwrap();
+        assert_eq!(chunk.unwrap().unwrap(), "first\n");
+
+        // starts on the first output, when nothing is written to fd 4
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("sh", &["-c", "echo first; sleep 30"]);
+        let resp = tokio::time::timeout(timeout, handler(rx, req, None, &args, &State::default()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let mut body = resp.into_body();
+        let chunk = tokio::time::timeout(timeout, hyper::body::HttpBody::data(&mut body))
+            .await
+            .unwrap();
+        assert_eq!(chunk.unwrap().unwrap(), "first\n");
+
+        // waits for fd 4 to close
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            wait_fd4_close: true,
+            ..command("sh", &["-c", r#"echo first; echo '{"status":201}' >&4"#])
+        };
+        let resp = handler(rx, req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::CREATED);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "first\n");
+    }
+
+    #[tokio::test]
+    async fn handler_sse() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("Last-Event-ID", 5)
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(
+            rx,
+            req,
+            None,
+            &command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    echo '{"sse":true}' >&4
+                    jq -r .last_event_id <&3
+                    printf '%s\n' '{"event":"greeting","id":6,"data":"hello\nworld"}'
+                    "#,
+                ],
+            ),
+            &State::default(),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(
+            body,
+            indoc! {"
+            data: 5
+
+            event: greeting
+            id: 6
+            data: hello
+            data: world
+
+            "}
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_topics() {
+        let timeout = std::time::Duration::from_secs(5);
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let args = Args {
+            topics: true,
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    if [ "$(jq -r .method <&3)" = POST ]; then
+                        jq -c '{topic: "chat", data: .}' >&7
+                    else
+                        echo '{"subscribe":"chat"}' >&4
+                    fi
+                    "#,
+                ],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let mut subscription = resp.into_body();
+
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .body(r#"{"text":"hi"}"#.into())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        let want = "id: 1\ndata: {\"text\":\"hi\"}\n\n";
+        let chunk = tokio::time::timeout(timeout, hyper::body::HttpBody::data(&mut subscription))
+            .await
+            .unwrap();
+        assert_eq!(chunk.unwrap().unwrap(), want);
+
+        // resuming replays what was missed
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("Last-Event-ID", 0)
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        let mut subscription = resp.into_body();
+        let chunk = tokio::time::timeout(timeout, hyper::body::HttpBody::data(&mut subscription))
+            .await
+            .unwrap();
+        assert_eq!(chunk.unwrap().unwrap(), want);
+    }
+
+    #[tokio::test]
+    async fn handler_compress() {
+        use async_compression::tokio::bufread::GzipDecoder;
+
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("accept-encoding", "gzip")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            compress: true,
+            ..command("seq", &["1000"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        assert_eq!(resp.headers().get("vary").unwrap(), "accept-encoding");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded.lines().count(), 1000);
+
+        // a small inline body isn't worth compressing
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("accept-encoding", "gzip")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            compress: true,
+            ..command("sh", &["-c", r#"echo '{"body":"small"}' >&4"#])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert!(resp.headers().get("vary").is_none());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "small");
+
+        // a client that doesn't accept an encoding still gets a response that varies on it
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            compress: true,
+            ..command("seq", &["1000"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.headers().get("vary").unwrap(), "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn handler_decompress() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let body = compress::encode_all(b"zebody", compress::Encoding::Gzip).await;
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-encoding", "gzip")
+            .header("content-length", body.len())
+            .body(body.into())
+            .unwrap();
+        let args = Args {
+            decompress: true,
+            ..command("sh", &["-c", "cat; cat <&3"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let (body, meta) = body.split_at(6);
+        assert_eq!(body, "zebody");
+        let meta: Request = serde_json::from_str(meta).unwrap();
+        assert_eq!(meta.content_encoding.as_deref(), Some("gzip"));
+        assert!(meta.headers.get("content-encoding").is_none());
+        assert!(meta.headers.get("content-length").is_none());
+
+        // decoded bodies are cut off at --max-decompressed-size
+        let (mut sender, body) = hyper::Body::channel();
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-encoding", "gzip")
+            .body(body)
+            .unwrap();
+        let args = Args {
+            decompress: true,
+            max_decompressed_size: 3,
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"trap 'cut=1' URG; echo '{"status":200}' >&4; cat; echo ${cut:+cut}"#,
+                ],
+            )
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        // the command has set its trap by the time it has responded
+        let body = compress::encode_all(b"zebody", compress::Encoding::Gzip).await;
+        sender.send_data(body.into()).await.unwrap();
+        drop(sender);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "zebcut\n");
+
+        // as are bodies that fail to decode, and a command that doesn't trap SIGURG carries on
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-encoding", "gzip")
+            .body("not gzip".into())
+            .unwrap();
+        let args = Args {
+            decompress: true,
+            ..command("sh", &["-c", "cat; echo done"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "done\n");
+    }
+
+    #[test]
+    fn args_max_headers() {
+        let parse =
+            |max: &str| Args::try_parse_from(["http-sh", "--max-headers", max, ":0", "true"]);
+        assert_eq!(parse("100").unwrap().max_headers, 100);
+        assert!(parse("101").is_err());
+        assert!(parse("0").is_err());
+    }
+
+    #[tokio::test]
+    async fn handler_limits() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        let ran = d.path().join("ran");
+
+        // a declared body over the limit is rejected before the command runs
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-length", 6)
+            .body("zebody".into())
+            .unwrap();
+        let args = Args {
+            max_body_size: Some(3),
+            ..command("touch", &[ran.to_str().unwrap()])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!ran.exists());
+
+        // a streamed body is cut off at the limit
+        let (mut sender, body) = hyper::Body::channel();
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .body(body)
+            .unwrap();
+        let args = Args {
+            max_body_size: Some(3),
+            ..command("sh", &["-c", r#"echo '{"status":200}' >&4; cat"#])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        sender.send_data("zebody".into()).await.unwrap();
+        drop(sender);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "zeb");
+
+        // too many headers
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .header("x-one", "1")
+            .header("x-two", "2")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            max_headers: 1,
+            ..command("echo", &["hi"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(
+            resp.status(),
+            hyper::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_expect_continue() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let script = |status: u16| Args {
+            expect_continue: true,
+            ..command(
+                "sh",
+                &["-c", &format!(r#"echo '{{"status":{}}}' >&4; cat"#, status)],
+            )
+        };
+
+        // the body is passed along once the command accepts the request
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("expect", "100-continue")
+            .body("zebody".into())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &script(200), &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "zebody");
+
+        // and left unread when it's rejected
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("expect", "100-continue")
+            .body("zebody".into())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &script(403), &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "");
+
+        // a command that reads stdin first gets the body after --expect-continue-timeout
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("expect", "100-continue")
+            .body("zebody".into())
+            .unwrap();
+        let args = Args {
+            expect_continue: true,
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"body=$(cat); echo '{"status":201}' >&4; echo "$body""#,
+                ],
+            )
+        };
+        let resp = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            handler(rx.clone(), req, None, &args, &State::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::CREATED);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "zebody\n");
     }
 
     #[tokio::test]
-    async fn handler_response_empty() {
+    async fn handler_multipart() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let body = "--X\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            hello\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\r\n\
+            zebody\r\n\
+            --X--\r\n";
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(body.into())
+            .unwrap();
+        let args = Args {
+            multipart: true,
+            ..command("sh", &["-c", "cat <&3"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let meta: Request = serde_json::from_slice(&body).unwrap();
+        let form = meta.form.unwrap();
+        assert_eq!(
+            form["title"],
+            vec![http_sh::FormField::Value("hello".into())]
+        );
+        let http_sh::FormField::File { path, size, .. } = &form["upload"][0] else {
+            panic!("expected a file");
+        };
+        assert_eq!(*size, 6);
+
+        // the spooled file is removed once the command has exited
+        for _ in 0..50 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(!path.exists());
+
+        // a malformed body is rejected
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body("garbage".into())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn handler_parse_body() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let args = Args {
+            parse_body: true,
+            ..command("sh", &["-c", "cat <&3; cat"])
+        };
+        let request = |content_type: &str, body: &'static str| {
+            hyper::Request::post("https://api.cross.stream/")
+                .header("content-type", content_type)
+                .header("content-length", body.len())
+                .body(body.into())
+                .unwrap()
+        };
+
+        for (content_type, body, parsed) in [
+            (
+                "application/x-www-form-urlencoded",
+                "name=zebody&n=1",
+                Some(json!({"name": "zebody", "n": "1"})),
+            ),
+            (
+                "application/json; charset=utf-8",
+                r#"{"name":"zebody"}"#,
+                Some(json!({"name": "zebody"})),
+            ),
+            ("application/json", "{", None),
+            ("text/plain", "zebody", None),
+        ] {
+            let req = request(content_type, body);
+            let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+            let output = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let output = std::str::from_utf8(&output).unwrap();
+            let (meta, raw) = output.split_once('\n').unwrap();
+            let meta: Request = serde_json::from_str(meta).unwrap();
+            assert_eq!(meta.body, parsed);
+            // stdin still gets the raw body
+            assert_eq!(raw, body);
+        }
+
+        // bodies over the cap are left unparsed
+        let args = Args {
+            parse_body_max_size: 4,
+            ..args
+        };
+        let req = request("application/json", r#"{"name":"zebody"}"#);
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        let output = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let output = std::str::from_utf8(&output).unwrap();
+        let (meta, _) = output.split_once('\n').unwrap();
+        let meta: Request = serde_json::from_str(meta).unwrap();
+        assert_eq!(meta.body, None);
+
+        // chunked bodies are parsed up to the cap too, and stdin still gets all of a longer one
+        let chunked = |chunks: &'static [&'static str], trailers: bool| {
+            let (mut sender, body) = hyper::Body::channel();
+            tokio::spawn(async move {
+                for chunk in chunks {
+                    sender.send_data((*chunk).into()).await.unwrap();
+                }
+                if trailers {
+                    let mut trailers = http::header::HeaderMap::new();
+                    trailers.insert("x-checksum", "abc".parse().unwrap());
+                    sender.send_trailers(trailers).await.unwrap();
+                }
+            });
+            hyper::Request::post("https://api.cross.stream/")
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap()
+        };
+        let args = Args {
+            parse_body_max_size: 16,
+            ..args
+        };
+        for (chunks, parsed) in [
+            (&[r#"{"n":"#, "1}"][..], Some(json!({"n": 1}))),
+            (&[r#"{"name":"#, r#""zebody","n":1}"#][..], None),
+        ] {
+            let resp = handler(
+                rx.clone(),
+                chunked(chunks, false),
+                None,
+                &args,
+                &State::default(),
+            )
+            .await;
+            let output = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let output = std::str::from_utf8(&output).unwrap();
+            let (meta, raw) = output.split_once('\n').unwrap();
+            let meta: Request = serde_json::from_str(meta).unwrap();
+            assert_eq!(meta.body, parsed);
+            assert_eq!(raw, chunks.concat());
+        }
+
+        // request trailers still reach the command once a body has been buffered
+        let trailers_args = Args {
+            parse_body: true,
+            trailers: true,
+            ..command("sh", &["-c", "cat >/dev/null; cat <&6"])
+        };
+        let req = chunked(&[r#"{"n":1}"#], true);
+        let resp = handler(rx.clone(), req, None, &trailers_args, &State::default()).await;
+        let output = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(output, "{\"x-checksum\":\"abc\"}\n");
+
+        // a body that fails to arrive is a bad request
+        let (sender, body) = hyper::Body::channel();
+        sender.abort();
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-type", "application/json")
+            .body(body)
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn handler_concurrency() {
         let (_tx, rx) = tokio::sync::watch::channel(false);
+        let args = Args {
+            max_concurrency: Some(1),
+            queue_size: 1,
+            ..command(
+                "sh",
+                &["-c", r#"echo '{"status":200}' >&4; cat <&3; sleep 0.2"#],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let request = || {
+            hyper::Request::get("https://api.cross.stream/")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+
+        // the first command holds the only slot until it exits
+        let resp = handler(rx.clone(), request(), None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+
+        // so the next request waits its turn
+        let queued = tokio::spawn({
+            let (rx, args, state) = (rx.clone(), args.clone(), state.clone());
+            async move { handler(rx, request(), None, &args, &state).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        // and with the queue full, another is turned away
+        let resp = handler(rx.clone(), request(), None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "30");
+
+        let resp = queued.await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let meta: Request = serde_json::from_slice(&body).unwrap();
+        assert_eq!(meta.queue_depth, Some(0));
+        assert!(meta.queue_wait_ms.unwrap() >= 50);
+    }
+
+    #[tokio::test]
+    async fn handler_concurrency_internal() {
+        // an internal redirect runs under the request's slot, while its first command lingers
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let args = Args {
+            max_concurrency: Some(1),
+            queue_timeout: 1,
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    if [ "$(jq -r .path <&3)" = /next ]; then
+                        echo next
+                    else
+                        echo '{"internal":"/next"}' >&4
+                        exec 4>&- >&-
+                        sleep 2
+                    fi
+                    "#,
+                ],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
         let req = hyper::Request::get("https://api.cross.stream/")
             .body(hyper::Body::empty())
             .unwrap();
-        let resp = handler(
-            rx,
-            req,
-            None,
-            &None,
-            &"sh".into(),
-            &vec![
-                "-c".into(),
-                r#"
-                cat > /dev/null
-                "#
-                .into(),
-            ],
-        )
-        .await;
+        let resp = handler(rx, req, None, &args, &state).await;
         assert_eq!(resp.status(), hyper::StatusCode::OK);
-        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain");
         let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
-        let body = std::str::from_utf8(&body).unwrap();
-        assert_eq!(body, "");
+        assert_eq!(body, "next\n");
+    }
+
+    #[test]
+    fn launch_cwd() {
+        let d = tempfile::tempdir().unwrap();
+        let file = d.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let launch = |cwd: &Path| {
+            let args = Args {
+                cwd: Some(cwd.to_path_buf()),
+                ..command("true", &[])
+            };
+            Launch::new(&args, std::env::var_os).map(|_| ())
+        };
+        assert!(launch(d.path()).is_ok());
+        assert!(launch(&file).is_err());
+        assert!(launch(&d.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn launch_file_root() {
+        let d = tempfile::tempdir().unwrap();
+        let launch = |file_root: Vec<PathBuf>| {
+            let args = Args {
+                landlock_ro: vec![d.path().to_path_buf()],
+                file_root,
+                ..command("true", &[])
+            };
+            Launch::new(&args, std::env::var_os).map(|_| ())
+        };
+        // the server opens `file` itself, outside of the command's confinement
+        assert!(launch(vec![]).is_err());
+        assert!(launch(vec![d.path().to_path_buf()]).is_ok());
+    }
+
+    #[test]
+    fn args_workers() {
+        let parse = |arg: &[&str]| {
+            let args = ["http-sh", "--workers", "1"].iter().chain(arg);
+            Args::try_parse_from(args.chain([":0", "true"].iter()))
+        };
+        assert!(parse(&[]).is_ok());
+        // workers are spawned once, without any of what's applied to each command
+        assert!(parse(&["--user", "nobody"]).is_err());
+        assert!(parse(&["--sandbox"]).is_err());
+        assert!(parse(&["--rlimit-cpu", "1"]).is_err());
+        assert!(parse(&["--seccomp-deny", "@network"]).is_err());
+        assert!(parse(&["--pty"]).is_err());
+        let args = ["http-sh", "--workers", "0", ":0", "true"];
+        assert!(Args::try_parse_from(args).is_err());
     }
 
     #[tokio::test]
-    async fn handler_response_meta() {
+    async fn handler_workers() {
         let (_tx, rx) = tokio::sync::watch::channel(false);
-        let req = hyper::Request::get("https://api.cross.stream/notfound")
+        let args = Args {
+            workers: Some(1),
+            worker_max_requests: Some(2),
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    n=0
+                    while read -r meta; do
+                        n=$((n+1))
+                        body=""
+                        while read -r len && [ "$len" != 0 ]; do
+                            body="$body$(dd bs=1 count=$len 2>/dev/null)"
+                        done
+                        out="$$ $n $body"
+                        echo '{"status":201}'
+                        printf '%s\n%s0\n' "${#out}" "$out"
+                    done
+                    "#,
+                ],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+
+        let mut seen = Vec::new();
+        for body in ["a", "b", "c"] {
+            let req = hyper::Request::post("https://api.cross.stream/")
+                .body(body.into())
+                .unwrap();
+            let resp = handler(rx.clone(), req, None, &args, &state).await;
+            assert_eq!(resp.status(), hyper::StatusCode::CREATED);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            seen.push(body.split(' ').map(String::from).collect::<Vec<_>>());
+        }
+        // the worker handles requests in turn, and is replaced after two
+        assert_eq!(seen[0][1..], ["1", "a"]);
+        assert_eq!(seen[1][1..], ["2", "b"]);
+        assert_eq!(seen[0][0], seen[1][0]);
+        assert_eq!(seen[2][1..], ["1", "c"]);
+        assert_ne!(seen[2][0], seen[0][0]);
+
+        // a worker that doesn't follow the protocol is a bad gateway
+        let args = Args {
+            workers: Some(1),
+            ..command("sh", &["-c", "read -r meta; echo oops"])
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_GATEWAY);
+
+        // nor does one that announces a frame over the maximum, which is replaced
+        let args = Args {
+            workers: Some(1),
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    read -r meta
+                    while read -r len && [ "$len" != 0 ]; do :; done
+                    echo '{}'
+                    pid=$$
+                    printf '%s\n%s%s\n' "${#pid}" "$pid" 99999999999
+                    cat >/dev/null
+                    "#,
+                ],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let mut pids = Vec::new();
+        for _ in 0..2 {
+            let req = hyper::Request::get("https://api.cross.stream/")
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = handler(rx.clone(), req, None, &args, &state).await;
+            let body = hyper::body::to_bytes(resp.into_body()).await;
+            pids.push(body.map(|body| body.to_vec()).unwrap_or_default());
+        }
+        assert_ne!(pids[0], pids[1]);
+
+        // a worker that can't be spawned is an error on the server's side
+        let args = Args {
+            workers: Some(1),
+            ..command("/nonexistent", &[])
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn handler_workers_body() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let echo = r#"
+            while read -r meta; do
+                body=""
+                while read -r len && [ "$len" != 0 ]; do
+                    body="$body$(dd bs=1 count=$len 2>/dev/null)"
+                done
+                out="$$ $body"
+                echo '{}'
+                printf '%s\n%s0\n' "${#out}" "$out"
+            done
+        "#;
+
+        // a body read ahead to be parsed still reaches the worker
+        let args = Args {
+            workers: Some(1),
+            parse_body: true,
+            ..command("sh", &["-c", echo])
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-type", "application/json")
+            .body(r#"{"a":1}"#.into())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(body.split_once(' ').unwrap().1, r#"{"a":1}"#);
+
+        // a worker that answers before its body is complete can't be reused
+        let args = Args {
+            workers: Some(1),
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    r#"
+                    read -r meta
+                    echo '{}'
+                    pid=$$
+                    printf '%s\n%s0\n' "${#pid}" "$pid"
+                    cat >/dev/null
+                    "#,
+                ],
+            )
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let mut pids = Vec::new();
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let (sender, body) = hyper::Body::channel();
+            senders.push(sender);
+            let req = hyper::Request::post("https://api.cross.stream/")
+                .body(body)
+                .unwrap();
+            let resp = handler(rx.clone(), req, None, &args, &state).await;
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            pids.push(body.to_vec());
+        }
+        assert_ne!(pids[0], pids[1]);
+    }
+
+    #[tokio::test]
+    async fn handler_prespawn() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        let started = d.path().join("started");
+        let script = format!(
+            "echo $$ >> {}; read -r meta <&3; echo $$",
+            started.to_str().unwrap()
+        );
+        let args = Args {
+            prespawn: Some(1),
+            ..command("sh", &["-c", &script])
+        };
+        let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+        let wait = std::time::Duration::from_millis(100);
+
+        // a command is started before any request arrives
+        tokio::time::sleep(wait).await;
+        let pids = std::fs::read_to_string(&started).unwrap();
+        assert_eq!(pids.lines().count(), 1);
+
+        // and handed the first request
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), pids);
+
+        // then replaced
+        tokio::time::sleep(wait).await;
+        let pids = std::fs::read_to_string(&started).unwrap();
+        assert_eq!(pids.lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn handler_spawn_error() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        let missing = d.path().join("missing");
+        let cases = [
+            command(missing.to_str().unwrap(), &[]),
+            Args {
+                cgroup: Some(missing.clone()),
+                ..command("true", &[])
+            },
+            Args {
+                prespawn: Some(1),
+                ..command(missing.to_str().unwrap(), &[])
+            },
+        ];
+        for args in cases {
+            let state = State::new(&args, Launch::new(&args, std::env::var_os).unwrap());
+            let req = hyper::Request::get("https://api.cross.stream/")
+                .body(hyper::Body::empty())
+                .unwrap();
+            let resp = handler(rx.clone(), req, None, &args, &state).await;
+            assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_rlimits() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+
+        // a command that runs out of CPU time is killed before it responds
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            rlimit_cpu: Some(1),
+            ..command("sh", &["-c", "while :; do :; done"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "command killed by SIGXCPU\n");
+
+        // limits apply from the start
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            rlimit_nofile: Some(64),
+            ..command("sh", &["-c", "ulimit -n"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "64\n");
+
+        // a command that closes its output is waited on, however long it takes to be killed
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("sh", &["-c", "exec 4>&- >&-; sleep 0.2; kill -KILL $$"]);
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+
+        // while one that exits normally still gets its 200
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("sh", &["-c", "exec 4>&- >&-; sleep 0.2"]);
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn handler_user() {
+        // switching users takes root
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("sh", &["-c", "id -u; id -G"]);
+        let identity = privileges::resolve(Some("nobody"), None).unwrap().unwrap();
+        let state = launched(Launch {
+            identity: Some(identity.clone()),
+            ..Default::default()
+        });
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let groups: Vec<String> = identity.groups.iter().map(|gid| gid.to_string()).collect();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            format!("{}\n{}\n", identity.uid, groups.join(" "))
+        );
+
+        // a command started before --drop-privileges has taken effect still switches
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            user: Some("nobody".into()),
+            drop_privileges: true,
+            ..command("id", &["-u"])
+        };
+        let resp = handler(rx, req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, format!("{}\n", identity.uid));
+    }
+
+    #[tokio::test]
+    async fn handler_sandbox() {
+        let Ok(sandbox) = sandbox::Sandbox::new(&[], false, None) else {
+            return;
+        };
+        // namespaces may not be available where the tests run
+        if sandbox.probe().is_err() {
+            return;
+        }
+        let state = launched(Launch {
+            sandbox: Some(Arc::new(sandbox)),
+            ..Default::default()
+        });
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command(
+            "sh",
+            &[
+                "-c",
+                "echo $$; touch /usr/x 2>/dev/null || echo ro; touch /tmp/x && ls /tmp; \
+                 test -e /root || echo hidden; grep -c : /proc/net/dev",
+            ],
+        );
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        // the command's pid is 2, after the namespace's init, and only loopback is left
+        assert_eq!(body, "2\nro\nx\nhidden\n1\n");
+
+        // a command killed inside the sandbox is reported the same as one outside
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("sh", &["-c", "kill -KILL $$"]);
+        let resp = handler(rx.clone(), req, None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "command killed by SIGKILL\n");
+
+        // uploads are bound in read-only, at the paths the command is given
+        let body = "--X\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\r\n\
+            zebody\r\n\
+            --X--\r\n";
+        let req = hyper::Request::post("https://api.cross.stream/")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(body.into())
+            .unwrap();
+        let args = Args {
+            multipart: true,
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    "path=$(grep -o '\"path\":\"[^\"]*' <&3 | tail -n 1 | cut -d '\"' -f 4); \
+                     cat \"$path\" && echo; echo 2>/dev/null >> \"$path\" || echo ro",
+                ],
+            )
+        };
+        let resp = handler(rx, req, None, &args, &state).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "zebody\nro\n");
+    }
+
+    #[tokio::test]
+    async fn handler_confine() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+
+        // landlock may not be enabled where the tests run
+        let d = tempfile::tempdir().unwrap();
+        let read: Vec<PathBuf> = ["/bin", "/usr", "/lib", "/lib64", "/etc"]
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect();
+        let write = [d.path().to_path_buf(), PathBuf::from("/dev/null")];
+        if let Ok(rules) = confine::Rules::new(&read, &write) {
+            let req = hyper::Request::get("https://api.cross.stream/")
+                .body(hyper::Body::empty())
+                .unwrap();
+            let script = format!(
+                "echo hi > {0}/ok && cat {0}/ok; ls /root 2>/dev/null || echo denied",
+                d.path().display()
+            );
+            let args = command("sh", &["-c", &script]);
+            let state = launched(Launch {
+                landlock: Some(rules),
+                ..Default::default()
+            });
+            let resp = handler(rx.clone(), req, None, &args, &state).await;
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(body, "hi\ndenied\n");
+        }
+
+        // a denied system call kills the command
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = command("bash", &["-c", "echo > /dev/tcp/127.0.0.1/1"]);
+        let state = launched(Launch {
+            seccomp: Some(Arc::new(
+                confine::seccomp(&["@network".to_string()]).unwrap(),
+            )),
+            ..Default::default()
+        });
+        let resp = handler(rx, req, None, &args, &state).await;
+        assert_eq!(resp.status(), hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "command killed by SIGSYS, a blocked system call\n");
+    }
+
+    #[tokio::test]
+    async fn handler_environment() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+        let d = tempfile::tempdir().unwrap();
+        // stands in for http-sh's environment
+        let env = HashMap::from([
+            ("HTTP_SH_TEST_KEPT", "kept"),
+            ("HTTP_SH_TEST_DROPPED", "dropped"),
+        ]);
+        let env = |name: String| env.get(name.as_str()).map(OsString::from);
+
+        let req = hyper::Request::get("https://api.cross.stream/")
             .body(hyper::Body::empty())
             .unwrap();
+        let args = Args {
+            cwd: Some(d.path().to_path_buf()),
+            env: vec![("GREETING".to_string(), "hi".to_string())],
+            env_clear: true,
+            env_allow: vec!["HTTP_SH_TEST_KEPT".to_string()],
+            path: Some("/usr/bin:/bin".to_string()),
+            ..command(
+                "sh",
+                &[
+                    "-c",
+                    "pwd; echo $GREETING $HTTP_SH_TEST_KEPT ${HTTP_SH_TEST_DROPPED-unset} $PATH",
+                ],
+            )
+        };
         let resp = handler(
             rx,
             req,
             None,
-            &None,
-            &"sh".into(),
-            &vec![
-                "-c".into(),
-                r#"
-                echo '{"status":404,"headers":{"content-type":"text/markdown"}}' >&4
-                echo '# Not Found'
-                jq -r .path <&3
-                "#
-                .into(),
-            ],
+            &args,
+            &State::new(&args, Launch::new(&args, env).unwrap()),
         )
         .await;
-        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
-        assert_eq!(resp.headers().get("content-type").unwrap(), "text/markdown");
         let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
-        let body = std::str::from_utf8(&body).unwrap();
         assert_eq!(
-            body,
-            indoc! {r#"
-            # Not Found
-            /notfound
-            "#}
+            std::str::from_utf8(&body).unwrap(),
+            format!(
+                "{}\nhi kept unset /usr/bin:/bin\n",
+                d.path().canonicalize().unwrap().display()
+            )
         );
     }
 
+    #[tokio::test]
+    async fn handler_pty() {
+        let (_tx, rx) = tokio::sync::watch::channel(false);
+
+        // stdout is a terminal, whose output is passed through as is
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            pty: true,
+            ..command("sh", &["-c", "test -t 1 && echo tty; stty size <&1"])
+        };
+        let resp = handler(rx.clone(), req, None, &args, &State::default()).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "tty\n24 80\n");
+
+        // or with line endings translated when cooked
+        let req = hyper::Request::get("https://api.cross.stream/")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let args = Args {
+            pty: true,
+            pty_size: pty::parse_size("120x40").unwrap(),
+            pty_cooked: true,
+            ..command("sh", &["-c", "test -t 1 && echo tty; stty size <&1"])
+        };
+        let resp = handler(rx, req, None, &args, &State::default()).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "tty\r\n40 120\r\n");
+    }
+
     #[tokio::test]
     async fn handler_static() {
         let (_tx, rx) = tokio::sync::watch::channel(false);
@@ -467,7 +3688,7 @@ mod tests {
         let filename = subdir.join("index.html");
         std::fs::write(&filename, "hello world").unwrap();
 
-        let static_path = Some(d.into_path());
+        let static_path = Some(d.keep());
 
         // static file exists
         let req = hyper::Request::get("https://api.cross.stream/static/")
@@ -477,9 +3698,11 @@ mod tests {
             rx.clone(),
             req,
             None,
-            &static_path,
-            &"echo".into(),
-            &vec!["hello world".into()],
+            &Args {
+                static_path: static_path.clone(),
+                ..command("echo", &["hello world"])
+            },
+            &State::default(),
         )
         .await;
         assert_eq!(resp.status(), hyper::StatusCode::OK);
@@ -500,9 +3723,11 @@ mod tests {
             rx.clone(),
             req,
             None,
-            &static_path,
-            &"echo".into(),
-            &vec!["hello world".into()],
+            &Args {
+                static_path: static_path.clone(),
+                ..command("echo", &["hello world"])
+            },
+            &State::default(),
         )
         .await;
         assert_eq!(resp.status(), hyper::StatusCode::OK);
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use tokio::io::AsyncWriteExt;
+
+use http_sh::FormField;
+
+/// A form's fields, by name. A name can be given more than once, say for several files.
+pub type Form = HashMap<String, Vec<FormField>>;
+
+/// Why a form couldn't be read.
+#[derive(Debug)]
+pub enum Error {
+    /// The body isn't valid `multipart/form-data`, or it ran over its size limit
+    Multipart(multer::Error),
+    /// An uploaded file couldn't be spooled to disk
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// The status to answer the request with.
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Error::Multipart(e) if is_too_large(e) => http::StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Multipart(_) => http::StatusCode::BAD_REQUEST,
+            Error::Io(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                http::StatusCode::INSUFFICIENT_STORAGE
+            }
+            Error::Io(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Multipart(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "failed to spool upload: {}", e),
+        }
+    }
+}
+
+impl From<multer::Error> for Error {
+    fn from(e: multer::Error) -> Self {
+        Error::Multipart(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The boundary of a `multipart/form-data` request, if that's what it is.
+pub fn boundary(headers: &http::HeaderMap) -> Option<String> {
+    let content_type = headers.get("content-type")?.to_str().ok()?;
+    multer::parse_boundary(content_type).ok()
+}
+
+/// Parses a `multipart/form-data` body, spooling file parts into `dir`.
+pub async fn parse(
+    body: hyper::Body,
+    boundary: String,
+    dir: &std::path::Path,
+    limit: Option<u64>,
+) -> Result<Form, Error> {
+    let mut constraints = multer::Constraints::new();
+    if let Some(limit) = limit {
+        constraints = constraints.size_limit(multer::SizeLimit::new().whole_stream(limit));
+    }
+    let mut multipart = multer::Multipart::with_constraints(body, boundary, constraints);
+    read_fields(&mut multipart, dir).await
+}
+
+/// Whether parsing failed because the body ran over its size limit.
+fn is_too_large(e: &multer::Error) -> bool {
+    match e {
+        multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => true,
+        multer::Error::StreamReadFailed(e) => {
+            e.downcast_ref::<multer::Error>().is_some_and(is_too_large)
+        }
+        _ => false,
+    }
+}
+
+async fn read_fields(
+    multipart: &mut multer::Multipart<'_>,
+    dir: &std::path::Path,
+) -> Result<Form, Error> {
+    let mut form = Form::new();
+    let mut files = 0;
+
+    while let Some(mut field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+
+        let Some(filename) = field.file_name().map(|filename| filename.to_string()) else {
+            let value = FormField::Value(field.text().await?);
+            form.entry(name).or_default().push(value);
+            continue;
+        };
+
+        // spooled files are named by position, so the client's filename never touches the disk
+        files += 1;
+        let path = dir.join(format!("file-{}", files));
+        let content_type = field.content_type().map(|mime| mime.to_string());
+        let mut file = tokio::fs::File::create(&path).await?;
+        let mut size = 0;
+        while let Some(chunk) = field.chunk().await? {
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        form.entry(name).or_default().push(FormField::File {
+            path,
+            filename: Some(filename).filter(|filename| !filename.is_empty()),
+            content_type,
+            size,
+        });
+    }
+
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            "content-type",
+            "multipart/form-data; boundary=X".parse().unwrap(),
+        );
+        let body = "--X\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            hello\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"../a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            zebody\r\n\
+            --X\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"b.txt\"\r\n\r\n\
+            second\r\n\
+            --X--\r\n";
+
+        let boundary = boundary(&headers).unwrap();
+        let form = parse(body.into(), boundary.clone(), dir.path(), None)
+            .await
+            .unwrap();
+        assert_eq!(form["title"], vec![FormField::Value("hello".into())]);
+        let path = dir.path().join("file-1");
+        assert_eq!(
+            form["upload"][0],
+            FormField::File {
+                path: path.clone(),
+                filename: Some("../a.txt".into()),
+                content_type: Some("text/plain".into()),
+                size: 6,
+            }
+        );
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "zebody");
+
+        // every field with a name is kept, in order
+        let FormField::File { path, .. } = &form["upload"][1] else {
+            panic!("expected a file");
+        };
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "second");
+
+        // bodies over the limit are refused
+        let res = parse(body.into(), boundary.clone(), dir.path(), Some(16)).await;
+        assert_eq!(
+            res.unwrap_err().status(),
+            http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // as are files that can't be spooled
+        let missing = dir.path().join("missing");
+        let res = parse(body.into(), boundary, &missing, None).await;
+        assert_eq!(
+            res.unwrap_err().status(),
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        // other content types are left alone
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        assert!(super::boundary(&headers).is_none());
+    }
+}
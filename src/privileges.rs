@@ -0,0 +1,94 @@
+use nix::unistd::{Gid, Group, Uid, User};
+
+/// The user, group and supplementary groups commands run as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub uid: Uid,
+    pub gid: Gid,
+    pub groups: Vec<Gid>,
+}
+
+/// Resolves `--user` and `--group`, each a name or a numeric id. The group defaults to the
+/// user's primary group, and the supplementary groups are the user's from the group database.
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, String> {
+    if user.is_none() && group.is_none() {
+        return Ok(None);
+    }
+
+    let user = match user {
+        Some(user) => {
+            let found = match user.parse::<u32>() {
+                Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+                Err(_) => User::from_name(user),
+            };
+            Some(
+                found
+                    .map_err(|e| format!("looking up user {}: {}", user, e))?
+                    .ok_or_else(|| format!("no such user: {}", user))?,
+            )
+        }
+        None => None,
+    };
+
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => {
+                Group::from_name(group)
+                    .map_err(|e| format!("looking up group {}: {}", group, e))?
+                    .ok_or_else(|| format!("no such group: {}", group))?
+                    .gid
+            }
+        },
+        None => user.as_ref().unwrap().gid,
+    };
+
+    let (uid, groups) = match &user {
+        Some(user) => {
+            let name = std::ffi::CString::new(user.name.as_str()).unwrap();
+            let groups = nix::unistd::getgrouplist(&name, gid)
+                .map_err(|e| format!("looking up groups for {}: {}", user.name, e))?;
+            (user.uid, groups)
+        }
+        None => (Uid::effective(), vec![gid]),
+    };
+
+    if !Uid::effective().is_root() && (uid != Uid::effective() || gid != Gid::effective()) {
+        return Err("--user and --group need http-sh to be started as root".to_string());
+    }
+
+    Ok(Some(Identity { uid, gid, groups }))
+}
+
+/// Switches the calling process to `identity`, for a command or, with `--drop-privileges`, the
+/// server itself. The groups go first, as they can't be changed once the user has been.
+pub fn assume(identity: &Identity) -> nix::Result<()> {
+    nix::unistd::setgroups(&identity.groups)?;
+    nix::unistd::setgid(identity.gid)?;
+    nix::unistd::setuid(identity.uid)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(None, None), Ok(None));
+
+        let uid = Uid::effective();
+        let current = resolve(Some(&uid.to_string()), None).unwrap().unwrap();
+        assert_eq!(current.uid, uid);
+        assert!(current.groups.contains(&current.gid));
+
+        assert_eq!(
+            resolve(Some("no-such-user-here"), None),
+            Err("no such user: no-such-user-here".to_string())
+        );
+        assert_eq!(
+            resolve(Some(&uid.to_string()), Some("no-such-group-here")),
+            Err("no such group: no-such-group-here".to_string())
+        );
+    }
+}
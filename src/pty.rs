@@ -0,0 +1,94 @@
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use nix::pty::Winsize;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The parent's end of a pseudo-terminal a command writes its stdout to. Once the command's end
+/// is closed, reads fail with `EIO`, which is reported as the end of the output.
+pub struct Pty {
+    master: AsyncFd<OwnedFd>,
+}
+
+/// Opens a pseudo-terminal of the given size, returning the parent's end and the end to hand to
+/// the command as its stdout. Output is passed through as is, unless `cooked`, when line endings
+/// are translated to `\r\n`.
+pub fn open(size: Winsize, cooked: bool) -> std::io::Result<(Pty, OwnedFd)> {
+    let pty = nix::pty::openpty(Some(&size), None)?;
+    if !cooked {
+        use nix::sys::termios;
+        let mut attrs = termios::tcgetattr(&pty.slave)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &attrs)?;
+    }
+
+    // neither end is left open in other commands
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+    for fd in [pty.master.as_raw_fd(), pty.slave.as_raw_fd()] {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    let fd = pty.master.as_raw_fd();
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+
+    // SAFETY: the master is an open OwnedFd, moved into the AsyncFd, so nothing else can close
+    // it or reuse its number while it's registered. It's only closed when the AsyncFd is dropped
+    let master =
+        unsafe { AsyncFd::register_with_interest(pty.master, tokio::io::Interest::READABLE) }?;
+    Ok((Pty { master }, pty.slave))
+}
+
+/// Parses a `--pty-size` of COLSxROWS.
+pub fn parse_size(s: &str) -> Result<Winsize, String> {
+    let parse = || {
+        let (cols, rows) = s.split_once('x')?;
+        Some(Winsize {
+            ws_col: cols.parse().ok()?,
+            ws_row: rows.parse().ok()?,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        })
+    };
+    parse().ok_or_else(|| format!("expected COLSxROWS, got {:?}", s))
+}
+
+impl AsyncRead for Pty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.master.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| {
+                nix::unistd::read(fd.as_raw_fd(), unfilled).map_err(std::io::Error::from)
+            }) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) if e.raw_os_error() == Some(nix::libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        let size = parse_size("120x40").unwrap();
+        assert_eq!((size.ws_col, size.ws_row), (120, 40));
+        assert!(parse_size("120").is_err());
+        assert!(parse_size("x40").is_err());
+    }
+}
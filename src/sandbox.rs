@@ -0,0 +1,538 @@
+use std::ffi::{CStr, CString};
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{ForkResult, Pid};
+
+/// Paths bound read-only when no `--sandbox-ro` is given, if they exist.
+const DEFAULT_RO: &[&str] = &["/bin", "/sbin", "/usr", "/lib", "/lib32", "/lib64", "/etc"];
+
+/// Device files bound into the sandbox's /dev.
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+
+/// Runs commands in new user, mount, PID and IPC namespaces, and optionally a network namespace
+/// with no interfaces up. The command sees a read-only root holding only the allowed paths, a
+/// private /tmp, /proc for its own PID namespace, and a few devices.
+///
+/// Everything a command needs is worked out up front by `new`, so that `enter` can run between
+/// fork and exec.
+#[derive(Debug)]
+pub struct Sandbox {
+    flags: CloneFlags,
+    /// The contents of uid_map and gid_map, when a user namespace is needed
+    maps: Option<(Vec<u8>, Vec<u8>)>,
+    /// An empty directory that the new root is mounted over
+    staging: tempfile::TempDir,
+    root: CString,
+    dirs: Vec<CString>,
+    files: Vec<CString>,
+    binds: Vec<Bind>,
+    tmp: CString,
+    proc: CString,
+    /// Where the command starts, inside the new root
+    cwd: CString,
+}
+
+/// A directory bound read-only into a single command's sandbox, at the same path as outside it.
+/// It's bound after the private /tmp is mounted, so it can be somewhere under /tmp.
+#[derive(Debug)]
+pub struct ExtraBind {
+    /// The directory and its parents, inside the new root
+    dirs: Vec<CString>,
+    bind: Bind,
+}
+
+#[derive(Debug)]
+struct Bind {
+    source: CString,
+    target: CString,
+    /// Flags to remount the bind with, keeping any that the source mount locks
+    remount: Option<MsFlags>,
+}
+
+impl Sandbox {
+    pub fn new(ro: &[PathBuf], network: bool, cwd: Option<&Path>) -> Result<Self, String> {
+        let mut flags =
+            CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWIPC;
+        if !network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        // root can create the other namespaces directly; anyone else needs a user namespace,
+        // where they're mapped to themselves
+        let maps = (!nix::unistd::Uid::effective().is_root()).then(|| {
+            flags |= CloneFlags::CLONE_NEWUSER;
+            let uid = nix::unistd::Uid::effective();
+            let gid = nix::unistd::Gid::effective();
+            (
+                format!("{} {} 1\n", uid, uid).into_bytes(),
+                format!("{} {} 1\n", gid, gid).into_bytes(),
+            )
+        });
+
+        let staging = tempfile::Builder::new()
+            .prefix("http-sh-sandbox")
+            .tempdir()
+            .map_err(|e| format!("creating sandbox root: {}", e))?;
+        let mut sandbox = Sandbox {
+            flags,
+            maps,
+            root: cstring(staging.path()),
+            tmp: cstring(&staging.path().join("tmp")),
+            proc: cstring(&staging.path().join("proc")),
+            cwd: cstring(cwd.unwrap_or(Path::new("/"))),
+            staging,
+            dirs: Vec::new(),
+            files: Vec::new(),
+            binds: Vec::new(),
+        };
+
+        let ro: Vec<PathBuf> = if ro.is_empty() {
+            DEFAULT_RO
+                .iter()
+                .map(PathBuf::from)
+                .filter(|path| path.exists())
+                .collect()
+        } else {
+            ro.to_vec()
+        };
+        for path in &ro {
+            if !path.is_absolute() {
+                return Err(format!(
+                    "sandbox paths must be absolute: {}",
+                    path.display()
+                ));
+            }
+            let stat = nix::sys::statvfs::statvfs(path.as_path())
+                .map_err(|e| format!("sandbox path {}: {}", path.display(), e))?;
+            sandbox.bind(path, Some(MsFlags::MS_RDONLY | locked(stat.flags())));
+        }
+        // anywhere else, the command would start in an empty directory or none at all
+        if let Some(cwd) = cwd.filter(|cwd| !ro.iter().any(|path| cwd.starts_with(path))) {
+            return Err(format!(
+                "--cwd {} isn't under any of the --sandbox-ro paths",
+                cwd.display()
+            ));
+        }
+        for device in DEVICES {
+            sandbox.bind(Path::new(device), None);
+        }
+        sandbox.dir(Path::new("/tmp"));
+        sandbox.dir(Path::new("/proc"));
+        Ok(sandbox)
+    }
+
+    /// Checks the namespaces can be created, by creating them in a short-lived child.
+    pub fn probe(&self) -> Result<(), String> {
+        let flags = self.flags;
+        let result = match unsafe { nix::unistd::fork() }.map_err(|e| e.to_string())? {
+            ForkResult::Child => {
+                let code = match unshare(flags) {
+                    Ok(()) => 0,
+                    Err(e) => e as i32,
+                };
+                unsafe { nix::libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => waitpid(child, None).map_err(|e| e.to_string())?,
+        };
+        match result {
+            WaitStatus::Exited(_, 0) => Ok(()),
+            WaitStatus::Exited(_, errno) => {
+                let e = nix::errno::Errno::from_i32(errno);
+                Err(match self.maps {
+                    Some(_) => format!(
+                        "--sandbox needs unprivileged user namespaces, which are unavailable: {}",
+                        e
+                    ),
+                    None => format!("--sandbox couldn't create namespaces: {}", e),
+                })
+            }
+            status => Err(format!("--sandbox probe failed: {:?}", status)),
+        }
+    }
+
+    /// Works out how to bind `path` into a command's sandbox, ahead of `enter`.
+    pub fn extra(&self, path: &Path) -> std::io::Result<ExtraBind> {
+        let stat = nix::sys::statvfs::statvfs(path)?;
+        let mut ancestors: Vec<&Path> = path.ancestors().filter(|p| *p != Path::new("/")).collect();
+        ancestors.reverse();
+        let inside = |path: &Path| self.staging.path().join(path.strip_prefix("/").unwrap());
+        Ok(ExtraBind {
+            dirs: ancestors
+                .into_iter()
+                .map(|dir| cstring(&inside(dir)))
+                .collect(),
+            bind: Bind {
+                source: cstring(path),
+                target: cstring(&inside(path)),
+                remount: Some(MsFlags::MS_RDONLY | locked(stat.flags())),
+            },
+        })
+    }
+
+    /// Moves the calling process into the sandbox, with `extra` bound in.
+    ///
+    /// A new PID namespace only takes effect for children, so this forks twice: the calling
+    /// process waits on an init for the namespace, which waits on the process that goes on to
+    /// exec the command. Both pass on SIGUSR1, SIGUSR2 and SIGURG, and the calling process exits
+    /// the way the command did. The command stays in the calling process's group, so signals
+    /// sent to the group reach it directly.
+    pub fn enter(&self, extra: Option<&ExtraBind>) -> std::io::Result<()> {
+        if let Some((uid_map, gid_map)) = &self.maps {
+            unshare(CloneFlags::CLONE_NEWUSER)?;
+            write(c"/proc/self/setgroups", b"deny")?;
+            write(c"/proc/self/uid_map", uid_map)?;
+            write(c"/proc/self/gid_map", gid_map)?;
+        }
+        unshare(self.flags - CloneFlags::CLONE_NEWUSER)?;
+
+        // the init reports a command killed by a signal back over this pipe
+        let (report_reader, report_writer) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+        if let ForkResult::Parent { child } = unsafe { nix::unistd::fork() }? {
+            for signal in [
+                Signal::SIGTERM,
+                Signal::SIGINT,
+                Signal::SIGHUP,
+                Signal::SIGQUIT,
+            ] {
+                unsafe { signal::signal(signal, SigHandler::SigIgn) }?;
+            }
+            keep_only(report_reader);
+            match supervise(child) {
+                Some(WaitStatus::Exited(_, code)) => {
+                    let mut signal = [0];
+                    match nix::unistd::read(0, &mut signal) {
+                        Ok(1) => die_by(signal[0] as i32),
+                        _ => exit(code),
+                    }
+                }
+                Some(WaitStatus::Signaled(_, signal, _)) => die_by(signal as i32),
+                _ => exit(1),
+            }
+        }
+
+        // the namespace's init: it dies with the calling process, and takes the namespace with it
+        nix::sys::prctl::set_pdeathsig(Signal::SIGKILL)?;
+        self.mount(extra)?;
+
+        if let ForkResult::Parent { child } = unsafe { nix::unistd::fork() }? {
+            keep_only(report_writer);
+            match supervise(child) {
+                Some(WaitStatus::Exited(_, code)) => exit(code),
+                Some(WaitStatus::Signaled(_, signal, _)) => {
+                    // init can't be killed by its own signals, so it passes the signal on instead
+                    let _ = nix::unistd::write(0, &[signal as u8]);
+                    exit(128 + signal as i32)
+                }
+                _ => exit(1),
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the new root over the staging directory and switches to it.
+    fn mount(&self, extra: Option<&ExtraBind>) -> nix::Result<()> {
+        const NONE: Option<&str> = None;
+        let dir = Mode::from_bits_truncate(0o755);
+
+        mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)?;
+        mount(
+            Some("tmpfs"),
+            self.root.as_c_str(),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            NONE,
+        )?;
+        for path in &self.dirs {
+            nix::unistd::mkdir(path.as_c_str(), dir)?;
+        }
+        for path in &self.files {
+            let fd = open(
+                path.as_c_str(),
+                OFlag::O_CREAT | OFlag::O_WRONLY,
+                Mode::from_bits_truncate(0o644),
+            )?;
+            nix::unistd::close(fd)?;
+        }
+        for bind in &self.binds {
+            bind.mount()?;
+        }
+        mount(
+            Some("tmpfs"),
+            self.tmp.as_c_str(),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            NONE,
+        )?;
+        if let Some(extra) = extra {
+            for path in &extra.dirs {
+                match nix::unistd::mkdir(path.as_c_str(), dir) {
+                    Ok(()) | Err(nix::errno::Errno::EEXIST) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            extra.bind.mount()?;
+        }
+        mount(
+            Some("proc"),
+            self.proc.as_c_str(),
+            Some("proc"),
+            MsFlags::empty(),
+            NONE,
+        )?;
+
+        nix::unistd::chdir(self.root.as_c_str())?;
+        nix::unistd::pivot_root(".", ".")?;
+        umount2(".", MntFlags::MNT_DETACH)?;
+        nix::unistd::chdir(self.cwd.as_c_str())?;
+        mount(
+            NONE,
+            "/",
+            NONE,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            NONE,
+        )
+    }
+
+    fn bind(&mut self, path: &Path, remount: Option<MsFlags>) {
+        let target = self.staging.path().join(path.strip_prefix("/").unwrap());
+        if path.is_dir() {
+            self.dir(path);
+        } else {
+            if let Some(parent) = path.parent() {
+                self.dir(parent);
+            }
+            self.files.push(cstring(&target));
+        }
+        self.binds.push(Bind {
+            source: cstring(path),
+            target: cstring(&target),
+            remount,
+        });
+    }
+
+    /// Adds `path` and its parents to the directories created in the new root.
+    fn dir(&mut self, path: &Path) {
+        let mut ancestors: Vec<&Path> = path.ancestors().filter(|p| *p != Path::new("/")).collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            let dir = cstring(
+                &self
+                    .staging
+                    .path()
+                    .join(ancestor.strip_prefix("/").unwrap()),
+            );
+            if !self.dirs.contains(&dir) {
+                self.dirs.push(dir);
+            }
+        }
+    }
+}
+
+impl Bind {
+    fn mount(&self) -> nix::Result<()> {
+        const NONE: Option<&str> = None;
+        mount(
+            Some(self.source.as_c_str()),
+            self.target.as_c_str(),
+            NONE,
+            MsFlags::MS_BIND,
+            NONE,
+        )?;
+        if let Some(flags) = self.remount {
+            mount(
+                NONE,
+                self.target.as_c_str(),
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | flags,
+                NONE,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The flags of a mount that a bind of it in a user namespace has to keep.
+fn locked(flags: nix::sys::statvfs::FsFlags) -> MsFlags {
+    use nix::sys::statvfs::FsFlags;
+    [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ]
+    .into_iter()
+    .filter(|(st, _)| flags.contains(*st))
+    .fold(MsFlags::empty(), |acc, (_, ms)| acc | ms)
+}
+
+fn cstring(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn write(path: &CStr, contents: &[u8]) -> std::io::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY, Mode::empty())?;
+    let written = nix::unistd::write(fd, contents);
+    nix::unistd::close(fd)?;
+    written?;
+    Ok(())
+}
+
+/// The process that signals are passed on to, in each of the processes `enter` leaves waiting.
+static FORWARD_TO: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: nix::libc::c_int) {
+    unsafe { nix::libc::kill(FORWARD_TO.load(Ordering::SeqCst), signal) };
+}
+
+/// Closes every file descriptor but `fd`, which is moved to 0, so the waiting processes don't
+/// hold the server's sockets or the command's pipes open.
+fn keep_only(fd: RawFd) {
+    unsafe {
+        nix::libc::dup2(fd, 0);
+        // close_range needs Linux 5.9; before that, the open descriptors are listed from /proc
+        if nix::libc::syscall(nix::libc::SYS_close_range, 1, u32::MAX, 0) != 0 {
+            close_listed();
+        }
+    }
+}
+
+/// Closes every descriptor above 0 listed in /proc/self/fd, reading the directory with
+/// getdents64 into a buffer on the stack rather than allocating.
+fn close_listed() {
+    use nix::libc;
+
+    let path = b"/proc/self/fd\0";
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let dir = unsafe { libc::open(path.as_ptr().cast(), flags) };
+    if dir < 0 {
+        return;
+    }
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = unsafe { libc::syscall(libc::SYS_getdents64, dir, buf.as_mut_ptr(), buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        // each entry is a linux_dirent64: d_ino (8 bytes), d_off (8), d_reclen (2), d_type (1)
+        // and then the NUL terminated name
+        let mut offset = 0;
+        while offset < n as usize {
+            let reclen = u16::from_ne_bytes([buf[offset + 16], buf[offset + 17]]) as usize;
+            let name = &buf[offset + 19..offset + reclen];
+            let fd = name
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .fold(0, |fd: RawFd, b| fd * 10 + (b - b'0') as RawFd);
+            // "." and ".." parse as 0, which is kept anyway
+            if fd > 0 && fd != dir {
+                unsafe { libc::close(fd) };
+            }
+            offset += reclen;
+        }
+    }
+    unsafe { libc::close(dir) };
+}
+
+/// Passes signals on to `child` until it exits, returning how it did.
+fn supervise(child: Pid) -> Option<WaitStatus> {
+    FORWARD_TO.store(child.as_raw(), Ordering::SeqCst);
+    for signal in [Signal::SIGUSR1, Signal::SIGUSR2, Signal::SIGURG] {
+        let _ = unsafe { signal::signal(signal, SigHandler::Handler(forward)) };
+    }
+    loop {
+        match waitpid(child, None) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            Ok(status @ (WaitStatus::Exited(..) | WaitStatus::Signaled(..))) => {
+                return Some(status)
+            }
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+fn exit(code: i32) -> ! {
+    unsafe { nix::libc::_exit(code) }
+}
+
+/// Exits by `signal`, so the server sees the command's fate as its own.
+fn die_by(signal: i32) -> ! {
+    unsafe {
+        nix::libc::signal(signal, nix::libc::SIG_DFL);
+        let mut set: nix::libc::sigset_t = std::mem::zeroed();
+        nix::libc::sigemptyset(&mut set);
+        nix::libc::sigaddset(&mut set, signal);
+        nix::libc::sigprocmask(nix::libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+        nix::libc::kill(nix::libc::getpid(), signal);
+    }
+    exit(128 + signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let d = tempfile::tempdir().unwrap();
+        let file = d.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let sandbox = Sandbox::new(&[d.path().to_path_buf(), file.clone()], false, None).unwrap();
+        assert!(sandbox.flags.contains(CloneFlags::CLONE_NEWNET));
+        let root = sandbox.staging.path();
+        let inside = |path: &Path| cstring(&root.join(path.strip_prefix("/").unwrap()));
+
+        // the parents of each path are created in order, ahead of the path itself
+        let target = inside(d.path());
+        let parent = inside(d.path().parent().unwrap());
+        let position = |path: &CString| sandbox.dirs.iter().position(|dir| dir == path);
+        assert!(position(&parent).unwrap() < position(&target).unwrap());
+        assert!(sandbox.files.contains(&inside(&file)));
+        assert!(sandbox.files.contains(&inside(Path::new("/dev/null"))));
+
+        let bind = sandbox.binds.iter().find(|b| b.target == target).unwrap();
+        assert!(bind.remount.unwrap().contains(MsFlags::MS_RDONLY));
+        assert!(sandbox.dirs.contains(&sandbox.tmp));
+
+        // a per-command bind creates its parents too, as it may be under the private /tmp
+        let extra = sandbox.extra(d.path()).unwrap();
+        assert_eq!(extra.dirs[extra.dirs.len() - 2..], [parent, target.clone()]);
+        assert_eq!(extra.bind.target, target);
+        assert!(extra.bind.remount.unwrap().contains(MsFlags::MS_RDONLY));
+        assert!(sandbox.extra(&d.path().join("missing")).is_err());
+
+        assert!(Sandbox::new(&[PathBuf::from("relative")], true, None).is_err());
+        assert!(Sandbox::new(&[PathBuf::from("/no/such/path")], true, None).is_err());
+        assert!(Sandbox::new(&[d.path().to_path_buf()], true, Some(d.path())).is_ok());
+        assert!(Sandbox::new(&[d.path().to_path_buf()], true, Some(Path::new("/usr"))).is_err());
+    }
+
+    #[test]
+    fn test_close_listed() {
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        match unsafe { nix::unistd::fork() }.unwrap() {
+            ForkResult::Child => {
+                close_listed();
+                let open = unsafe { nix::libc::fcntl(fd, nix::libc::F_GETFD) } >= 0;
+                exit(open as i32);
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt};
+
+pub const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+/// Frames a line of output as a server-sent event. A JSON object with any of `event`, `id`,
+/// `retry` or `data` is framed field by field; any other line is sent as the event's data.
+pub fn frame(line: &[u8]) -> Vec<u8> {
+    let fields = serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(line)
+        .ok()
+        .filter(|fields| {
+            ["event", "id", "retry", "data"]
+                .iter()
+                .any(|key| fields.contains_key(*key))
+        });
+
+    let mut event = Vec::new();
+    let Some(fields) = fields else {
+        push_data(&mut event, &String::from_utf8_lossy(line));
+        event.push(b'\n');
+        return event;
+    };
+
+    for key in ["event", "id", "retry"] {
+        match fields.get(key) {
+            Some(serde_json::Value::String(value)) => push_field(&mut event, key, value),
+            Some(serde_json::Value::Null) | None => {}
+            Some(value) => push_field(&mut event, key, &value.to_string()),
+        }
+    }
+    match fields.get("data") {
+        Some(serde_json::Value::String(data)) => push_data(&mut event, data),
+        Some(serde_json::Value::Null) | None => {}
+        Some(data) => push_data(&mut event, &data.to_string()),
+    }
+    event.push(b'\n');
+    event
+}
+
+fn push_field(event: &mut Vec<u8>, key: &str, value: &str) {
+    // a field value can't span lines
+    let value = value.lines().next().unwrap_or_default();
+    event.extend_from_slice(format!("{}: {}\n", key, value).as_bytes());
+}
+
+fn push_data(event: &mut Vec<u8>, data: &str) {
+    // each line of a multi-line payload gets its own data field
+    for line in data.split('\n') {
+        event.extend_from_slice(format!("data: {}\n", line.trim_end_matches('\r')).as_bytes());
+    }
+}
+
+/// Frames each line read from `output` as an event, with a keep-alive comment whenever the
+/// output has been quiet for `keep_alive`.
+pub fn frames(
+    output: impl AsyncRead + Unpin + Send + 'static,
+    keep_alive: Duration,
+) -> tokio::io::DuplexStream {
+    let (reader, mut writer) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut lines = tokio::io::BufReader::new(output).split(b'\n');
+        loop {
+            let event = tokio::select! {
+                line = lines.next_segment() => match line {
+                    Ok(Some(line)) => frame(&line),
+                    _ => break,
+                },
+                _ = tokio::time::sleep(keep_alive) => KEEP_ALIVE.to_vec(),
+            };
+            if writer.write_all(&event).await.is_err() {
+                break;
+            }
+        }
+    });
+    reader
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn framed(line: &str) -> String {
+        String::from_utf8(frame(line.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_frame_line() {
+        assert_eq!(framed("hello"), "data: hello\n\n");
+        assert_eq!(
+            framed(r#"{"date":"today"}"#),
+            "data: {\"date\":\"today\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_frame_fields() {
+        assert_eq!(
+            framed(r#"{"event":"message","id":7,"retry":1000,"data":"one\ntwo"}"#),
+            "event: message\nid: 7\nretry: 1000\ndata: one\ndata: two\n\n"
+        );
+        assert_eq!(
+            framed(r#"{"event":"update","data":{"n":1}}"#),
+            "event: update\ndata: {\"n\":1}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frames_keep_alive() {
+        use tokio::io::AsyncReadExt;
+
+        let (output, mut input) = tokio::io::duplex(64);
+        let mut framed = frames(output, Duration::from_millis(10));
+
+        input.write_all(b"hello\n").await.unwrap();
+        let mut buf = [0; 64];
+        let n = framed.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"data: hello\n\n");
+
+        let n = framed.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], KEEP_ALIVE);
+
+        drop(input);
+        let mut rest = Vec::new();
+        framed.read_to_end(&mut rest).await.unwrap();
+    }
+}
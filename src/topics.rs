@@ -0,0 +1,286 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::{broadcast, watch};
+
+use crate::sse;
+
+/// A published event, already framed for a server-sent events stream.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub id: u64,
+    pub frame: Vec<u8>,
+}
+
+/// A message published by a command, one JSON object per line.
+#[derive(Deserialize, Debug)]
+pub struct Publish {
+    pub topic: String,
+    #[serde(default)]
+    pub event: Option<String>,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+struct Topic {
+    tx: broadcast::Sender<Event>,
+    replay: VecDeque<Event>,
+    last_id: u64,
+    /// When the last event was published
+    published: Instant,
+}
+
+#[derive(Default)]
+struct Topics {
+    live: HashMap<String, Topic>,
+    /// The last id of each dropped topic, so one that's published to again carries on from it
+    last_ids: HashMap<String, u64>,
+}
+
+/// An in-process hub of named topics. Each topic keeps its most recent events, so a
+/// subscriber can resume from a `Last-Event-ID`. A topic is dropped once it has no subscribers
+/// and nothing has been published to it for `ttl`.
+#[derive(Clone)]
+pub struct Hub {
+    topics: Arc<Mutex<Topics>>,
+    replay: usize,
+    ttl: Duration,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub::new(100, Duration::from_secs(300))
+    }
+}
+
+impl Hub {
+    pub fn new(replay: usize, ttl: Duration) -> Self {
+        Hub {
+            topics: Default::default(),
+            replay,
+            ttl,
+        }
+    }
+
+    fn topic<'a>(&self, topics: &'a mut Topics, name: &str) -> &'a mut Topic {
+        let last_ids = &mut topics.last_ids;
+        topics.live.retain(|name, topic| {
+            let keep = topic.tx.receiver_count() > 0 || topic.published.elapsed() < self.ttl;
+            if !keep {
+                last_ids.insert(name.clone(), topic.last_id);
+            }
+            keep
+        });
+        topics
+            .live
+            .entry(name.to_string())
+            .or_insert_with(|| Topic {
+                tx: broadcast::channel(self.replay.max(16)).0,
+                replay: VecDeque::new(),
+                last_id: last_ids.remove(name).unwrap_or(0),
+                published: Instant::now(),
+            })
+    }
+
+    pub fn publish(&self, message: Publish) -> u64 {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = self.topic(&mut topics, &message.topic);
+        topic.last_id += 1;
+        topic.published = Instant::now();
+
+        let mut fields = serde_json::Map::new();
+        fields.insert("id".into(), topic.last_id.into());
+        if let Some(event) = message.event {
+            fields.insert("event".into(), event.into());
+        }
+        fields.insert("data".into(), message.data);
+        let event = Event {
+            id: topic.last_id,
+            frame: sse::frame(&serde_json::to_vec(&fields).unwrap()),
+        };
+
+        topic.replay.push_back(event.clone());
+        while topic.replay.len() > self.replay {
+            topic.replay.pop_front();
+        }
+        // there may not be any subscribers
+        let _ = topic.tx.send(event);
+        topic.last_id
+    }
+
+    /// Subscribes to a topic, returning the buffered events after `last_event_id` along with a
+    /// receiver for new events.
+    pub fn subscribe(
+        &self,
+        name: &str,
+        last_event_id: Option<u64>,
+    ) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = self.topic(&mut topics, name);
+        let replay = match last_event_id {
+            Some(last) => topic
+                .replay
+                .iter()
+                .filter(|event| event.id > last)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (replay, topic.tx.subscribe())
+    }
+
+    /// Publishes each line read from a command's publish fd, until it's closed. Lines that aren't
+    /// a message are logged and skipped.
+    pub async fn publish_lines(
+        &self,
+        reader: impl tokio::io::AsyncRead + Unpin,
+        stamp: scru128::Scru128Id,
+    ) {
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str::<Publish>(&line) {
+                Ok(message) => {
+                    self.publish(message);
+                }
+                Err(e) => println!(
+                    "{}",
+                    serde_json::json!({
+                        "stamp": scru128::new(),
+                        "message": "publish",
+                        "request": stamp,
+                        "error": e.to_string(),
+                    })
+                ),
+            }
+        }
+    }
+}
+
+/// Streams a subscription to `sender` as server-sent events, until the client goes away or the
+/// server shuts down. A subscriber that falls too far behind is disconnected, so it can resume
+/// from its `Last-Event-ID`.
+pub async fn stream(
+    (replay, mut rx): (Vec<Event>, broadcast::Receiver<Event>),
+    mut sender: hyper::body::Sender,
+    keep_alive: Duration,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    for event in replay {
+        if sender.send_data(event.frame.into()).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let frame = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => event.frame,
+                Err(_) => return,
+            },
+            _ = tokio::time::sleep(keep_alive) => sse::KEEP_ALIVE.to_vec(),
+            status = shutdown_rx.changed() => {
+                if status.is_err() || *shutdown_rx.borrow() {
+                    return;
+                }
+                continue;
+            }
+        };
+        if sender.send_data(frame.into()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, data: &str) -> Publish {
+        Publish {
+            topic: topic.into(),
+            event: None,
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let hub = Hub::new(2, Duration::from_secs(300));
+        assert_eq!(hub.publish(message("chat", "one")), 1);
+        assert_eq!(hub.publish(message("chat", "two")), 2);
+        assert_eq!(hub.publish(message("chat", "three")), 3);
+        assert_eq!(hub.publish(message("other", "one")), 1);
+
+        let (replay, _) = hub.subscribe("chat", None);
+        assert!(replay.is_empty());
+
+        let (replay, _) = hub.subscribe("chat", Some(0));
+        let ids: Vec<_> = replay.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        let (replay, _) = hub.subscribe("chat", Some(2));
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].frame, b"id: 3\ndata: three\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let hub = Hub::default();
+        let (_, mut rx) = hub.subscribe("chat", None);
+        hub.publish(Publish {
+            topic: "chat".into(),
+            event: Some("message".into()),
+            data: serde_json::json!({"text": "hi"}),
+        });
+        let event = rx.recv().await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&event.frame).unwrap(),
+            "event: message\nid: 1\ndata: {\"text\":\"hi\"}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_evict() {
+        let hub = Hub::new(2, Duration::ZERO);
+
+        // a subscriber keeps the topic
+        let (_, rx) = hub.subscribe("chat", None);
+        hub.publish(message("chat", "one"));
+        hub.publish(message("other", "one"));
+        let (replay, _) = hub.subscribe("chat", Some(0));
+        assert_eq!(replay.len(), 1);
+        drop(rx);
+
+        // without one, it's dropped along with its events once they've expired
+        hub.publish(message("other", "two"));
+        let (replay, _) = hub.subscribe("chat", Some(0));
+        assert!(replay.is_empty());
+
+        // its ids carry on rather than starting over, so a returning subscriber isn't skipped
+        // past new events
+        assert_eq!(hub.publish(message("chat", "two")), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_shutdown() {
+        let hub = Hub::default();
+        let (sender, mut body) = hyper::Body::channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(stream(
+            hub.subscribe("chat", None),
+            sender,
+            Duration::from_secs(60),
+            shutdown_rx,
+        ));
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(hyper::body::HttpBody::data(&mut body).await.is_none());
+    }
+}
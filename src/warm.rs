@@ -0,0 +1,54 @@
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+
+/// Keeps a number of things, such as spawned commands, ready ahead of time. Whatever's taken is
+/// replaced in the background.
+pub struct Pool<T> {
+    ready: Mutex<mpsc::Receiver<T>>,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    pub fn new(size: usize, spawn: impl Fn() -> T + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel(size);
+        tokio::spawn(async move {
+            // a slot is reserved before spawning, so exactly `size` are kept ready
+            while let Ok(permit) = tx.reserve().await {
+                permit.send(spawn());
+            }
+        });
+        Pool {
+            ready: Mutex::new(rx),
+        }
+    }
+
+    /// Takes a ready one, if there is one.
+    pub fn take(&self) -> Option<T> {
+        self.ready.lock().unwrap().try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_pool() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let pool = Pool::new(2, {
+            let spawned = spawned.clone();
+            move || spawned.fetch_add(1, Ordering::SeqCst)
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+
+        assert_eq!(pool.take(), Some(0));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.take(), Some(1));
+        assert_eq!(pool.take(), Some(2));
+    }
+}
@@ -0,0 +1,207 @@
+use futures::{SinkExt, StreamExt};
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+/// The longest message a command can send with `websocket_frames`. One that claims to be longer
+/// closes the connection.
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// Whether the request asks to be upgraded to a WebSocket connection.
+pub fn is_upgrade(headers: &http::header::HeaderMap) -> bool {
+    let has_token = |name: &str, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .map(|value| {
+                    value
+                        .split(',')
+                        .any(|part| part.trim().eq_ignore_ascii_case(token))
+                })
+                .unwrap_or(false)
+        })
+    };
+    has_token("connection", "upgrade")
+        && has_token("upgrade", "websocket")
+        && headers.contains_key("sec-websocket-key")
+}
+
+/// The `Sec-WebSocket-Accept` value answering the request's `Sec-WebSocket-Key`.
+pub fn accept_key(headers: &http::header::HeaderMap) -> String {
+    let key = headers.get("sec-websocket-key").unwrap();
+    tokio_tungstenite::tungstenite::handshake::derive_accept_key(key.as_bytes())
+}
+
+/// Bridges an upgraded connection to the command: each incoming message is written to stdin
+/// as a line, and each line of stdout is sent as a message. With `frames`, messages are written
+/// and read as frames instead, so they can hold any bytes. Without it, a binary message closes
+/// the connection, as a line can't carry it. Returns once either side closes.
+pub async fn bridge(
+    on_upgrade: hyper::upgrade::OnUpgrade,
+    mut stdin: tokio::process::ChildStdin,
+    stdout: impl AsyncRead + Unpin,
+    frames: bool,
+) {
+    let upgraded = match on_upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(_) => return,
+    };
+    let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let inbound = async {
+        while let Some(Ok(message)) = ws_rx.next().await {
+            if let Message::Close(_) = message {
+                return None;
+            }
+            let written = match (frames, message) {
+                (true, message @ (Message::Text(_) | Message::Binary(_))) => {
+                    write_frame(&mut stdin, message).await
+                }
+                (false, Message::Text(text)) => {
+                    let mut line = text.into_bytes();
+                    line.push(b'\n');
+                    stdin.write_all(&line).await
+                }
+                (false, Message::Binary(_)) => {
+                    return Some(CloseFrame {
+                        code: CloseCode::Unsupported,
+                        reason: "binary messages need websocket_frames".into(),
+                    });
+                }
+                _ => continue,
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+        None
+    };
+
+    let outbound = async {
+        let mut stdout = tokio::io::BufReader::new(stdout);
+        while let Some(message) = read_message(&mut stdout, frames).await {
+            if ws_tx.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_tx.close().await;
+    };
+
+    let close = tokio::select! {
+        close = inbound => close,
+        _ = outbound => None,
+    };
+    if let Some(frame) = close {
+        let _ = ws_tx.send(Message::Close(Some(frame))).await;
+    }
+}
+
+/// Writes a message as a frame: a line holding its type, `text` or `binary`, and its length in
+/// bytes, then the message.
+async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: Message,
+) -> std::io::Result<()> {
+    let (kind, data) = match message {
+        Message::Text(text) => ("text", text.into_bytes()),
+        Message::Binary(data) => ("binary", data),
+        _ => return Ok(()),
+    };
+    writer
+        .write_all(format!("{} {}\n", kind, data.len()).as_bytes())
+        .await?;
+    writer.write_all(&data).await
+}
+
+/// Reads the command's next message, as a frame or as a line. A line is sent as text when it's
+/// valid UTF-8. Returns `None` once stdout closes, or on a frame that can't be read.
+async fn read_message(reader: &mut (impl AsyncBufRead + Unpin), frames: bool) -> Option<Message> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await.ok()? == 0 {
+        return None;
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    if !frames {
+        return Some(match String::from_utf8(line) {
+            Ok(text) => Message::Text(text),
+            Err(e) => Message::Binary(e.into_bytes()),
+        });
+    }
+
+    let header = std::str::from_utf8(&line).ok()?;
+    let (kind, len) = header.split_once(' ')?;
+    let len: usize = len.parse().ok().filter(|len| *len <= MAX_MESSAGE)?;
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await.ok()?;
+    match kind {
+        "text" => String::from_utf8(data).ok().map(Message::Text),
+        "binary" => Some(Message::Binary(data)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_upgrade() {
+        let mut headers = http::header::HeaderMap::new();
+        headers.insert("connection", "keep-alive, Upgrade".parse().unwrap());
+        headers.insert("upgrade", "websocket".parse().unwrap());
+        assert!(!is_upgrade(&headers));
+
+        headers.insert(
+            "sec-websocket-key",
+            "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+        );
+        assert!(is_upgrade(&headers));
+        assert_eq!(accept_key(&headers), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let mut written = Vec::new();
+        write_frame(&mut written, Message::Binary(b"a\nb".to_vec()))
+            .await
+            .unwrap();
+        write_frame(&mut written, Message::Text("hi".into()))
+            .await
+            .unwrap();
+        assert_eq!(written, b"binary 3\na\nbtext 2\nhi");
+
+        let mut reader = &written[..];
+        assert_eq!(
+            read_message(&mut reader, true).await,
+            Some(Message::Binary(b"a\nb".to_vec()))
+        );
+        assert_eq!(
+            read_message(&mut reader, true).await,
+            Some(Message::Text("hi".into()))
+        );
+        assert_eq!(read_message(&mut reader, true).await, None);
+
+        // a frame that's cut short, or too long, ends the messages
+        assert_eq!(read_message(&mut &b"binary 5\nab"[..], true).await, None);
+        let header = format!("binary {}\n", MAX_MESSAGE + 1);
+        assert_eq!(read_message(&mut header.as_bytes(), true).await, None);
+
+        let mut reader = &b"one\ntwo"[..];
+        assert_eq!(
+            read_message(&mut reader, false).await,
+            Some(Message::Text("one".into()))
+        );
+        assert_eq!(
+            read_message(&mut reader, false).await,
+            Some(Message::Text("two".into()))
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt as _;
+use serde_json::json;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use http_sh::Response;
+
+use crate::environment::Environment;
+
+/// The longest response frame a worker can send. One that claims to be longer breaks the
+/// protocol, and the worker is replaced rather than trusted with an allocation that size.
+const MAX_FRAME: usize = 1024 * 1024;
+
+/// How long the request body has to finish being written once the response is complete. A
+/// worker that answered without reading all of it can't be handed another request.
+const WRITE_GRACE: Duration = Duration::from_secs(1);
+
+/// Why a worker couldn't answer a request.
+#[derive(Debug)]
+pub enum Error {
+    /// No worker could be spawned
+    Spawn(std::io::Error),
+    /// The worker failed before it responded
+    Failed,
+}
+
+impl Error {
+    /// The status to answer the request with.
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Error::Spawn(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Failed => http::StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Spawn(e) => write!(f, "failed to spawn worker: {}", e),
+            Error::Failed => write!(f, "worker failed"),
+        }
+    }
+}
+
+/// A pool of long-lived commands, each handling one request at a time over stdin and stdout.
+///
+/// A request is written to a worker's stdin as its metadata on one line of JSON, followed by the
+/// body as frames: the length of a chunk in bytes on its own line, then the chunk. A zero length
+/// frame ends the body. The worker answers on stdout the same way: its response metadata on one
+/// line, then the response body as frames, each at most `MAX_FRAME` bytes.
+pub struct Pool {
+    command: String,
+    args: Vec<String>,
+    environment: Environment,
+    max_requests: Option<usize>,
+    idle: Mutex<Vec<Worker>>,
+    slots: Arc<Semaphore>,
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    served: usize,
+}
+
+impl Pool {
+    pub fn new(
+        command: &str,
+        args: &[String],
+        environment: Environment,
+        size: usize,
+        max_requests: Option<usize>,
+    ) -> Self {
+        let pool = Pool {
+            command: command.to_string(),
+            args: args.to_vec(),
+            environment,
+            max_requests,
+            idle: Mutex::new(Vec::new()),
+            slots: Arc::new(Semaphore::new(size)),
+        };
+        // a worker that can't be spawned now is tried again when a request needs it
+        let workers = (0..size).filter_map(|_| pool.spawn().ok()).collect();
+        *pool.idle.lock().unwrap() = workers;
+        pool
+    }
+
+    fn spawn(&self) -> std::io::Result<Worker> {
+        let mut command = tokio::process::Command::new(&self.command);
+        self.environment.apply(&mut command);
+        let spawned = command
+            .args(&self.args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                println!(
+                    "{}",
+                    json!({
+                        "stamp": scru128::new(),
+                        "message": "worker",
+                        "reason": "spawn failed",
+                        "error": e.to_string(),
+                    })
+                );
+                return Err(e);
+            }
+        };
+        Ok(Worker {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            served: 0,
+        })
+    }
+
+    /// Waits for a free worker. Workers that have exited since they were last used are replaced.
+    async fn checkout(&self) -> std::io::Result<(OwnedSemaphorePermit, Worker)> {
+        let permit = self.slots.clone().acquire_owned().await.unwrap();
+        let idle = self.idle.lock().unwrap().pop();
+        let worker = match idle {
+            Some(mut worker) => match worker.child.try_wait() {
+                Ok(None) => worker,
+                _ => {
+                    retire(worker, "exited");
+                    self.spawn()?
+                }
+            },
+            None => self.spawn()?,
+        };
+        Ok((permit, worker))
+    }
+
+    fn checkin(&self, worker: Worker) {
+        if self.max_requests.is_some_and(|max| worker.served >= max) {
+            retire(worker, "recycled");
+            return;
+        }
+        self.idle.lock().unwrap().push(worker);
+    }
+
+    /// Hands a request to a worker, returning its response metadata and a body streamed from its
+    /// frames. The request body is `prefix`, already read from it, followed by the rest of `body`.
+    /// `hold` is kept until the worker is done with the request. A worker that fails before it
+    /// responds is replaced.
+    pub async fn serve(
+        self: &Arc<Self>,
+        req_json: String,
+        prefix: Vec<u8>,
+        body: hyper::Body,
+        hold: impl Send + 'static,
+    ) -> Result<(Response, hyper::Body), Error> {
+        let (permit, worker) = self.checkout().await.map_err(Error::Spawn)?;
+        let Worker {
+            child,
+            mut stdin,
+            mut stdout,
+            served,
+        } = worker;
+
+        if stdin
+            .write_all(format!("{}\n", req_json).as_bytes())
+            .await
+            .is_err()
+        {
+            retire_child(child, served, "failed");
+            return Err(Error::Failed);
+        }
+        let mut writer = tokio::spawn(write_frames(stdin, prefix, body));
+
+        let mut line = String::new();
+        let response = match stdout.read_line(&mut line).await {
+            Ok(n) if n > 0 => serde_json::from_str::<Response>(&line).ok(),
+            _ => None,
+        };
+        let Some(response) = response else {
+            writer.abort();
+            retire_child(child, served, "failed");
+            return Err(Error::Failed);
+        };
+
+        let (sender, res_body) = hyper::Body::channel();
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let complete = read_frames(&mut stdout, sender).await;
+            let stdin = match tokio::time::timeout(WRITE_GRACE, &mut writer).await {
+                Ok(stdin) => stdin.ok().flatten(),
+                Err(_) => {
+                    writer.abort();
+                    None
+                }
+            };
+            match (complete, stdin) {
+                (true, Some(stdin)) => pool.checkin(Worker {
+                    child,
+                    stdin,
+                    stdout,
+                    served: served + 1,
+                }),
+                _ => retire_child(child, served + 1, "failed"),
+            }
+            drop(hold);
+            drop(permit);
+        });
+
+        Ok((response, res_body))
+    }
+}
+
+fn retire(worker: Worker, reason: &str) {
+    retire_child(worker.child, worker.served, reason);
+}
+
+/// Logs a worker leaving the pool. Dropping it kills the process.
+fn retire_child(child: Child, served: usize, reason: &str) {
+    println!(
+        "{}",
+        json!({
+            "stamp": scru128::new(),
+            "message": "worker",
+            "reason": reason,
+            "pid": child.id(),
+            "served": served,
+        })
+    );
+}
+
+/// Writes `prefix` and then the rest of the request body as frames, returning stdin once the body
+/// is complete.
+async fn write_frames(
+    mut stdin: ChildStdin,
+    prefix: Vec<u8>,
+    body: hyper::Body,
+) -> Option<ChildStdin> {
+    let prefix = futures::stream::iter([Ok(prefix.into())]);
+    let mut body = prefix.chain(body);
+    while let Some(chunk) = body.next().await {
+        let chunk: hyper::body::Bytes = chunk.ok()?;
+        if chunk.is_empty() {
+            continue;
+        }
+        stdin
+            .write_all(format!("{}\n", chunk.len()).as_bytes())
+            .await
+            .ok()?;
+        stdin.write_all(&chunk).await.ok()?;
+    }
+    stdin.write_all(b"0\n").await.ok()?;
+    stdin.flush().await.ok()?;
+    Some(stdin)
+}
+
+/// Forwards response frames to `sender`, returning true once the final frame has been read. The
+/// frames are read to the end even if the client goes away, so the worker can be reused.
+async fn read_frames(stdout: &mut BufReader<ChildStdout>, sender: hyper::body::Sender) -> bool {
+    let mut sender = Some(sender);
+    loop {
+        let mut line = String::new();
+        match stdout.read_line(&mut line).await {
+            Ok(n) if n > 0 => (),
+            _ => return false,
+        }
+        let Ok(len) = line.trim().parse::<usize>() else {
+            return false;
+        };
+        if len == 0 {
+            return true;
+        }
+        if len > MAX_FRAME {
+            return false;
+        }
+        let mut chunk = vec![0; len];
+        if stdout.read_exact(&mut chunk).await.is_err() {
+            return false;
+        }
+        if let Some(s) = sender.as_mut() {
+            if s.send_data(chunk.into()).await.is_err() {
+                sender = None;
+            }
+        }
+    }
+}
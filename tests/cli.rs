@@ -4,7 +4,7 @@ use std::str::FromStr;
 
 use std::process::Command;
 
-use sysinfo::SystemExt;
+use sysinfo::{ProcessExt, SystemExt};
 
 // Taken from:
 // https://github.com/assert-rs/assert_cmd/blob/e71a9f7b15596dd2aeea911bedbbd1859d84fa67/src/cargo.rs#L183-L208
@@ -95,7 +95,7 @@ fn connection_cleanup() {
     sys.refresh_all();
     assert!(sys.process(pid).is_some());
 
-    let _ = curl.kill().unwrap();
+    curl.kill().unwrap();
     let _ = curl.wait().unwrap();
 
     sys.refresh_all();
@@ -138,7 +138,7 @@ fn serve_unix() {
     assert_eq!(want.as_bytes(), got.stdout);
 
     // next , parse got.stdout to a Response and assert HOST header
-    let logline = loglines.next().unwrap().unwrap();
+    let logline = next_request_log(&mut loglines);
     let log: http_sh::Request = serde_json::from_str(&logline).unwrap();
     assert_eq!(log.proto, "HTTP/1.1");
     assert_eq!(log.authority, Some("localhost:5555".to_string()));
@@ -150,7 +150,7 @@ fn serve_unix() {
         "http://localhost:5555/",
     ]);
     assert_eq!(want.as_bytes(), got.stdout);
-    let logline = loglines.next().unwrap().unwrap();
+    let logline = next_request_log(&mut loglines);
     assert_eq!(log.authority, Some("localhost:5555".to_string()));
 
     serve.kill().unwrap();
@@ -222,3 +222,117 @@ fn serve_tcp() {
         .unwrap();
     assert_eq!(0, n, "stderr is not empty: \n---\n{}---\n", stderr);
 }
+
+#[test]
+fn serve_websocket() {
+    let http_sh = cargo_bin("http-sh");
+
+    let serve = Command::new(http_sh)
+        .arg(":0")
+        .arg("--")
+        .arg("bash")
+        .arg("-c")
+        .arg(
+            r#"
+            echo '{"websocket":true}' >&4
+            echo $$
+            while read -r line; do echo "echo: $line"; done
+            "#,
+        )
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut serve = scopeguard::guard(serve, |mut serve| {
+        let _ = serve.kill();
+    });
+
+    // read startup log line to ensure serve is ready
+    let stdout = serve.stdout.take().unwrap();
+    let mut stdout = std::io::BufReader::new(stdout);
+    let mut read = String::new();
+    stdout.read_line(&mut read).unwrap();
+    let log: serde_json::Value = serde_json::from_str(&read).unwrap();
+
+    let url = format!("ws://{}/", log["address"].as_str().unwrap());
+    let (mut ws, resp) = tungstenite::connect(url).unwrap();
+    assert_eq!(resp.status(), 101);
+
+    let pid = ws.read().unwrap().into_text().unwrap();
+    let pid = sysinfo::Pid::from_str(&pid).unwrap();
+
+    ws.send(tungstenite::Message::Text("hello".into())).unwrap();
+    assert_eq!(ws.read().unwrap().into_text().unwrap(), "echo: hello");
+
+    let mut sys = sysinfo::System::new_all();
+    sys.refresh_all();
+    assert!(sys.process(pid).is_some());
+
+    ws.close(None).unwrap();
+    while ws.read().is_ok() {}
+
+    // the command is terminated once the client goes away
+    for _ in 0..50 {
+        sys.refresh_all();
+        match sys.process(pid) {
+            Some(process) if process.status() != sysinfo::ProcessStatus::Zombie => {
+                std::thread::sleep(std::time::Duration::from_millis(100))
+            }
+            _ => break,
+        }
+    }
+    let process = sys.process(pid);
+    assert!(process.is_none() || process.unwrap().status() == sysinfo::ProcessStatus::Zombie);
+}
+
+#[test]
+fn serve_websocket_frames() {
+    let http_sh = cargo_bin("http-sh");
+
+    let serve = Command::new(http_sh)
+        .arg(":0")
+        .arg("--")
+        .arg("bash")
+        .arg("-c")
+        .arg(
+            r#"
+            echo '{"websocket":true,"websocket_frames":true}' >&4
+            while read -r kind len; do
+                echo "$kind $len"
+                dd bs=1 count="$len" status=none
+            done
+            "#,
+        )
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut serve = scopeguard::guard(serve, |mut serve| {
+        let _ = serve.kill();
+    });
+
+    let stdout = serve.stdout.take().unwrap();
+    let mut stdout = std::io::BufReader::new(stdout);
+    let mut read = String::new();
+    stdout.read_line(&mut read).unwrap();
+    let log: serde_json::Value = serde_json::from_str(&read).unwrap();
+
+    let url = format!("ws://{}/", log["address"].as_str().unwrap());
+    let (mut ws, _) = tungstenite::connect(url).unwrap();
+
+    // a binary message holding newlines comes back whole
+    let data = b"one\ntwo\n\0".to_vec();
+    ws.send(tungstenite::Message::Binary(data.clone())).unwrap();
+    assert_eq!(ws.read().unwrap(), tungstenite::Message::Binary(data));
+    ws.send(tungstenite::Message::Text("hi".into())).unwrap();
+    assert_eq!(ws.read().unwrap(), tungstenite::Message::Text("hi".into()));
+    ws.close(None).unwrap();
+}
+
+/// Skips over other log lines, such as each command's exit, to the next request.
+fn next_request_log(loglines: &mut impl Iterator<Item = std::io::Result<String>>) -> String {
+    loglines
+        .map(|line| line.unwrap())
+        .find(|line| line.contains(r#""message":"request""#))
+        .unwrap()
+}